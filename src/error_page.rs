@@ -0,0 +1,257 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::headers::Accept;
+
+/// Media types of error pages, by preference when the client accepts several
+const ERROR_TYPES: &'static [&'static str] = &["text/plain", "text/html", "application/problem+json", "application/json"];
+
+/// Format of an error page, negotiated with the `Accept` header of the request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    /// `text/html`
+    Html,
+    /// `application/problem+json`, as described in RFC 7807
+    Json,
+    /// `text/plain`
+    Text,
+}
+
+impl ErrorFormat {
+    /// The format for a client sending `accept`
+    ///
+    /// Plain text is used without the header or when no format is accepted
+    pub fn from_accept(accept: Option<&Accept>) -> ErrorFormat {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return ErrorFormat::Text,
+        };
+
+        match accept.negotiate(ERROR_TYPES) {
+            Some("text/html") => ErrorFormat::Html,
+            Some("application/problem+json") | Some("application/json") => ErrorFormat::Json,
+            _ => ErrorFormat::Text,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            ErrorFormat::Html => "text/html; charset=utf-8",
+            ErrorFormat::Json => "application/problem+json",
+            ErrorFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Extension of the files with pages in this format
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ErrorFormat::Html => "html",
+            ErrorFormat::Json => "json",
+            ErrorFormat::Text => "txt",
+        }
+    }
+}
+
+/// Renders the body of the responses for errors
+///
+/// Returning `None` sends the default body for the format. Closures taking
+/// the status, its description and the format are renderers too
+///
+/// #Examples
+///
+/// ```
+/// use std::env;
+/// use http_server::HttpServer;
+/// use http_server::error_page::{escape_html, ErrorFiles, ErrorFormat, ErrorTemplate};
+///
+/// let root = env::home_dir().unwrap();
+///
+/// // 404.html, 404.json or 404.txt from the root
+/// let mut config = HttpServer::builder();
+/// config.with_error_renderer(ErrorFiles::new(&root));
+///
+/// // the same page for every error
+/// let mut template = ErrorTemplate::new();
+/// template.with_html("<h1>{status}</h1><p>{description}</p>");
+/// config.with_error_renderer(template);
+///
+/// // anything else
+/// config.with_error_renderer(|status: i32, description: &str, format: ErrorFormat| {
+///     match format {
+///         ErrorFormat::Html => Some(format!("<h1>Oops, {}</h1>", escape_html(description))),
+///         _ => None,
+///     }
+/// });
+/// ```
+pub trait ErrorRenderer {
+    fn render(&self, status: i32, description: &str, format: ErrorFormat) -> Option<String>;
+}
+
+impl<F> ErrorRenderer for F where F: Fn(i32, &str, ErrorFormat) -> Option<String> {
+    fn render(&self, status: i32, description: &str, format: ErrorFormat) -> Option<String> {
+        self(status, description, format)
+    }
+}
+
+/// Renders errors from files named after the status in a directory
+///
+/// The page for a 404 in HTML is `404.html`, and `404.json` and `404.txt`
+/// in the other formats. Files are read for each error, so they can be
+/// changed while the server runs
+#[derive(Clone, Debug)]
+pub struct ErrorFiles {
+    dir: PathBuf,
+}
+
+impl ErrorFiles {
+    pub fn new(dir: &Path) -> ErrorFiles {
+        ErrorFiles {
+            dir: dir.to_owned(),
+        }
+    }
+}
+
+impl ErrorRenderer for ErrorFiles {
+    fn render(&self, status: i32, _description: &str, format: ErrorFormat) -> Option<String> {
+        let path = self.dir.join(format!("{}.{}", status, format.extension()));
+
+        let mut page = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut page)) {
+            Ok(_) => Some(page),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Renders errors from templates, one for each format
+///
+/// `{status}` and `{description}` are replaced by the values of the error,
+/// escaped for the format of the template
+#[derive(Clone, Debug, Default)]
+pub struct ErrorTemplate {
+    html: Option<String>,
+    json: Option<String>,
+    text: Option<String>,
+}
+
+impl ErrorTemplate {
+    pub fn new() -> ErrorTemplate {
+        ErrorTemplate::default()
+    }
+
+    pub fn with_html(&mut self, template: &str) -> &mut Self {
+        self.html = Some(template.to_owned());
+        self
+    }
+
+    pub fn with_json(&mut self, template: &str) -> &mut Self {
+        self.json = Some(template.to_owned());
+        self
+    }
+
+    pub fn with_text(&mut self, template: &str) -> &mut Self {
+        self.text = Some(template.to_owned());
+        self
+    }
+}
+
+impl ErrorRenderer for ErrorTemplate {
+    fn render(&self, status: i32, description: &str, format: ErrorFormat) -> Option<String> {
+        let (template, description) = match format {
+            ErrorFormat::Html => (&self.html, escape_html(description)),
+            ErrorFormat::Json => (&self.json, escape_json(description)),
+            ErrorFormat::Text => (&self.text, description.to_owned()),
+        };
+
+        template.as_ref().map(|template| {
+            template.replace("{status}", &status.to_string())
+                .replace("{description}", &description)
+        })
+    }
+}
+
+/// The renderer used for the errors sent by the server and its handlers
+///
+/// Without a renderer, or when it renders nothing, a short default page is sent
+#[derive(Clone, Default)]
+pub struct ErrorPages {
+    renderer: Option<Arc<ErrorRenderer + Send + Sync>>,
+}
+
+impl ErrorPages {
+    pub fn new<R>(renderer: R) -> ErrorPages
+            where R: ErrorRenderer + Send + Sync + 'static {
+        ErrorPages {
+            renderer: Some(Arc::new(renderer)),
+        }
+    }
+
+    /// The body of the page for `status` in `format`
+    pub fn render(&self, status: i32, description: &str, format: ErrorFormat) -> String {
+        self.renderer.as_ref()
+            .and_then(|renderer| renderer.render(status, description, format))
+            .unwrap_or_else(|| default_page(status, description, format))
+    }
+}
+
+impl fmt::Debug for ErrorPages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorPages")
+            .field("renderer", &self.renderer.is_some())
+            .finish()
+    }
+}
+
+fn default_page(status: i32, description: &str, format: ErrorFormat) -> String {
+    match format {
+        ErrorFormat::Html => {
+            format!("<html><head><title>{0} - {1}</title></head><body><h1>{0} - {1}</h1></body></html>",
+                    status, escape_html(description))
+        }
+        ErrorFormat::Json => {
+            format!("{{\"type\":\"about:blank\",\"title\":\"{}\",\"status\":{}}}",
+                    escape_json(description), status)
+        }
+        ErrorFormat::Text => format!("{} - {}", status, description),
+    }
+}
+
+/// Escape the characters with a special meaning in HTML
+pub fn escape_html(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Escape a value to put inside a JSON string
+pub fn escape_json(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
@@ -0,0 +1,452 @@
+use std::cmp;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::headers::{Headers, ContentType, ContentDisposition};
+
+/// Maximum size of the headers of each part of a multipart body
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+/// Errors decoding a form sent in the request body
+#[derive(Debug)]
+pub enum FormError {
+    /// The body does not have the expected `Content-Type`
+    UnsupportedContentType(String),
+    /// The body, or one of its parts, is bigger than allowed
+    TooLarge,
+    /// The body is not formatted as its `Content-Type` says
+    Malformed(String),
+    Io(io::Error),
+}
+
+impl FormError {
+    /// Status code and description of the response for this error
+    pub fn status(&self) -> (i32, &'static str) {
+        match *self {
+            FormError::UnsupportedContentType(_) => (415, "Unsupported Media Type"),
+            FormError::TooLarge => (413, "Payload Too Large"),
+            FormError::Malformed(_) => (400, "Bad Request"),
+            FormError::Io(ref e) if e.kind() == ErrorKind::InvalidData => (400, "Bad Request"),
+            FormError::Io(_) => (500, "Internal Server Error"),
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormError::UnsupportedContentType(ref content_type) => {
+                write!(f, "Unsupported form content type: '{}'", content_type)
+            }
+            FormError::TooLarge => write!(f, "Form too large"),
+            FormError::Malformed(ref reason) => write!(f, "Malformed form: {}", reason),
+            FormError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for FormError {
+    fn description(&self) -> &str {
+        match *self {
+            FormError::UnsupportedContentType(_) => "Unsupported form content type",
+            FormError::TooLarge => "Form too large",
+            FormError::Malformed(_) => "Malformed form",
+            FormError::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            FormError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormError {
+    fn from(error: io::Error) -> FormError {
+        FormError::Io(error)
+    }
+}
+
+/// Read all of `reader`, failing if it has more than `max_size` bytes
+pub fn read_limited<R: Read>(reader: R, max_size: u64) -> Result<Vec<u8>, FormError> {
+    let mut data = Vec::new();
+    try!(reader.take(max_size.saturating_add(1)).read_to_end(&mut data));
+
+    if data.len() as u64 > max_size {
+        return Err(FormError::TooLarge);
+    }
+
+    Ok(data)
+}
+
+/// Reader for the parts of a `multipart/form-data` body
+///
+/// Parts are read in order with `next_part`. Each part must be read, or
+/// skipped, before the next one is available, so files can be streamed
+/// to disk without keeping the whole body in memory
+///
+/// #Examples
+///
+/// ```
+/// use std::io::Read;
+/// use http_server::form::Multipart;
+///
+/// let mut body: &[u8] = b"--XyZ\r\n\
+///     Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+///     Holidays\r\n\
+///     --XyZ\r\n\
+///     Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+///     Content-Type: image/jpeg\r\n\r\n\
+///     <jpeg data>\r\n\
+///     --XyZ--\r\n";
+///
+/// let mut multipart = Multipart::new(&mut body, "XyZ");
+///
+/// let mut title = multipart.next_part().unwrap().unwrap();
+/// assert_eq!(title.name(), Some("title"));
+/// assert_eq!(title.text(1024).unwrap(), "Holidays");
+///
+/// let mut photo = multipart.next_part().unwrap().unwrap();
+/// assert_eq!(photo.filename(), Some("beach.jpg"));
+/// assert_eq!(photo.content_type().unwrap().mime, "image/jpeg");
+///
+/// let file = photo.save_temp(1024).unwrap();
+/// assert_eq!(file.size(), 11);
+///
+/// assert!(multipart.next_part().unwrap().is_none());
+/// ```
+pub struct Multipart<'a> {
+    reader: &'a mut Read,
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    eof: bool,
+    state: MultipartState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MultipartState {
+    /// Reading the data before the first part, or of a part
+    Data,
+    /// Just after a delimiter, before the headers of the next part
+    Delimiter,
+    /// After the closing delimiter
+    Done,
+}
+
+impl<'a> Multipart<'a> {
+    /// Read the parts of `reader`, separated by `boundary`
+    pub fn new(reader: &'a mut Read, boundary: &str) -> Multipart<'a> {
+        Multipart {
+            reader: reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // the first delimiter does not need the line break before it
+            buffer: b"\r\n".to_vec(),
+            eof: false,
+            state: MultipartState::Data,
+        }
+    }
+
+    /// Get the next part, skipping what was not read of the current one
+    ///
+    /// Returns `None` after the last part
+    pub fn next_part<'m>(&'m mut self) -> Result<Option<Part<'m, 'a>>, FormError> {
+        let mut skipped = [0; 1024];
+        while self.state == MultipartState::Data {
+            try!(self.read_data(&mut skipped));
+        }
+
+        if self.state == MultipartState::Done {
+            return Ok(None);
+        }
+
+        try!(self.fill_to(2));
+        if self.buffer.starts_with(b"--") {
+            self.state = MultipartState::Done;
+            return Ok(None);
+        }
+
+        let mut headers = Headers::new();
+        let mut headers_size = 0;
+        let mut first_line = true;
+
+        loop {
+            let line_end = try!(self.find_line_end(MAX_PART_HEADERS_SIZE - headers_size));
+            let line: Vec<u8> = self.buffer.drain(..line_end + 2).collect();
+            headers_size += line.len();
+
+            // the line after the delimiter may only have whitespace
+            if first_line {
+                first_line = false;
+                if line[..line_end].iter().all(|&b| b == b' ' || b == b'\t') {
+                    continue;
+                }
+                return Err(FormError::Malformed("Invalid boundary line".to_owned()));
+            }
+
+            if line_end == 0 {
+                break;
+            }
+
+            let line = match String::from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => return Err(FormError::Malformed("Invalid part header".to_owned())),
+            };
+
+            if let Err(e) = headers.parse(&line) {
+                return Err(FormError::Malformed(e.to_string()));
+            }
+        }
+
+        self.state = MultipartState::Data;
+        Part::new(self, headers).map(Some)
+    }
+
+    /// Read data of the current part, returning 0 once its delimiter is reached
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.state != MultipartState::Data || buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let available = match find(&self.buffer, &self.delimiter) {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.state = MultipartState::Delimiter;
+                    return Ok(0);
+                }
+                Some(position) => position,
+                // the end of the buffer may be the start of the delimiter
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+
+            if available > 0 {
+                let len = cmp::min(available, buf.len());
+                buf[..len].copy_from_slice(&self.buffer[..len]);
+                self.buffer.drain(..len);
+                return Ok(len);
+            }
+
+            if try!(self.fill()) == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "Multipart body ended before the closing boundary"));
+            }
+        }
+    }
+
+    /// Position of the next `\r\n` in the buffer, reading at most `limit` bytes
+    fn find_line_end(&mut self, limit: usize) -> Result<usize, FormError> {
+        loop {
+            if let Some(position) = find(&self.buffer, b"\r\n") {
+                if position + 2 > limit {
+                    return Err(FormError::TooLarge);
+                }
+                return Ok(position);
+            }
+
+            if self.buffer.len() >= limit {
+                return Err(FormError::TooLarge);
+            }
+
+            if try!(self.fill()) == 0 {
+                return Err(FormError::Malformed("Multipart body ended in the part headers".to_owned()));
+            }
+        }
+    }
+
+    /// Read until the buffer has at least `len` bytes
+    fn fill_to(&mut self, len: usize) -> Result<(), FormError> {
+        while self.buffer.len() < len {
+            if try!(self.fill()) == 0 {
+                return Err(FormError::Malformed("Multipart body ended after a boundary".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<usize, io::Error> {
+        if self.eof {
+            return Ok(0);
+        }
+
+        let mut chunk = [0; 8 * 1024];
+        let bytes_read = try!(self.reader.read(&mut chunk));
+        if bytes_read == 0 {
+            self.eof = true;
+        }
+
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+/// A part of a `multipart/form-data` body
+///
+/// The content of the part is read with `Read`, or with the helpers that
+/// keep it in memory or save it to a file up to a size limit
+pub struct Part<'m, 'a: 'm> {
+    multipart: &'m mut Multipart<'a>,
+    headers: Headers,
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<ContentType>,
+}
+
+impl<'m, 'a> Part<'m, 'a> {
+    fn new(multipart: &'m mut Multipart<'a>, headers: Headers) -> Result<Part<'m, 'a>, FormError> {
+        let disposition = match headers.typed::<ContentDisposition>() {
+            Ok(disposition) => disposition,
+            Err(e) => return Err(FormError::Malformed(e.to_string())),
+        };
+
+        let content_type = match headers.typed::<ContentType>() {
+            Ok(content_type) => content_type,
+            Err(e) => return Err(FormError::Malformed(e.to_string())),
+        };
+
+        Ok(Part {
+            multipart: multipart,
+            name: disposition.as_ref().and_then(|d| d.param("name")).map(|n| n.to_owned()),
+            filename: disposition.as_ref().and_then(|d| d.param("filename")).map(|n| n.to_owned()),
+            content_type: content_type,
+            headers: headers,
+        })
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Name of the form field
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|n| n.as_ref())
+    }
+
+    /// Name of the file as sent by the client, for file fields
+    ///
+    /// It should not be used as a path, since it is not sanitized
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_ref().map(|n| n.as_ref())
+    }
+
+    pub fn content_type(&self) -> Option<&ContentType> {
+        self.content_type.as_ref()
+    }
+
+    /// Read the content, failing if it is bigger than `max_size`
+    pub fn bytes(&mut self, max_size: u64) -> Result<Vec<u8>, FormError> {
+        read_limited(self, max_size)
+    }
+
+    /// Read the content as text, failing if it is bigger than `max_size`
+    pub fn text(&mut self, max_size: u64) -> Result<String, FormError> {
+        let bytes = try!(self.bytes(max_size));
+        String::from_utf8(bytes).map_err(|_| FormError::Malformed("Field is not valid UTF-8".to_owned()))
+    }
+
+    /// Save the content to a new file in `dir`, failing if it is bigger than `max_size`
+    ///
+    /// The file is removed when the returned `TempFile` is dropped,
+    /// unless it is moved somewhere else with `persist`
+    pub fn save(&mut self, dir: &Path, max_size: u64) -> Result<TempFile, FormError> {
+        let (mut file, temp) = try!(TempFile::create(dir));
+
+        let size = try!(io::copy(&mut self.take(max_size.saturating_add(1)), &mut file));
+        if size > max_size {
+            return Err(FormError::TooLarge);
+        }
+
+        try!(file.flush());
+        let mut temp = temp;
+        temp.size = size;
+        Ok(temp)
+    }
+
+    /// Save the content to a new file in the temporary directory of the system
+    ///
+    /// See `save`
+    pub fn save_temp(&mut self, max_size: u64) -> Result<TempFile, FormError> {
+        self.save(&env::temp_dir(), max_size)
+    }
+}
+
+impl<'m, 'a> Read for Part<'m, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.multipart.read_data(buf)
+    }
+}
+
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// File holding an uploaded part, removed when dropped
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    size: u64,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn create(dir: &Path) -> Result<(File, TempFile), io::Error> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+
+        loop {
+            let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+            let path = dir.join(format!("upload-{}-{}-{}", process::id(), nanos, count));
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    return Ok((file, TempFile {
+                        path: path,
+                        size: 0,
+                        persisted: false,
+                    }));
+                }
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Move the file to `path`, so it is kept after the `TempFile` is dropped
+    pub fn persist(mut self, path: &Path) -> Result<(), io::Error> {
+        if fs::rename(&self.path, path).is_err() {
+            // renaming fails across file systems
+            try!(fs::copy(&self.path, path));
+            try!(fs::remove_file(&self.path));
+        }
+
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
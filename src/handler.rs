@@ -1,28 +1,280 @@
 use std::error::Error;
 use std::any::Any;
 use std::fs::{self, File, Metadata};
-use std::io::{self, Write, ErrorKind};
+use std::io::{self, Read, Write, Seek, SeekFrom, ErrorKind};
 use std::marker::PhantomData;
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::{Path, PathBuf, Component};
+use std::time::UNIX_EPOCH;
 
 use conduit_mime_types::Types;
+use time::{self, Timespec};
 use url::percent_encoding as perc_enc;
 
+use ::error_page::{escape_html, escape_json};
+use ::headers::{format_http_date, parse_http_date, Accept, AcceptEncoding, Range};
 use ::response::Response;
 use ::request::Request;
 
+/// Methods `ServerHandler` responds to
+const ALLOWED_METHODS: &'static str = "GET, HEAD, OPTIONS";
+
+/// Content codings of precompressed files and the suffix of their names, by preference
+const PRECOMPRESSED: &'static [(&'static str, &'static str)] = &[("br", ".br"), ("gzip", ".gz")];
+
 pub struct FileMode;
 pub struct DirectoryMode;
 
+/// Serve files like `FileMode`, falling back to a single file for unknown pages
+///
+/// Meant for single page applications that route on the client: a `GET`
+/// for a missing path without an extension, from a client that accepts
+/// `text/html`, is answered with the fallback file. Missing files with an
+/// extension are still not found
+///
+/// #Examples
+///
+/// ```
+/// use std::env;
+/// use http_server::handler::{ServerHandler, SpaMode};
+///
+/// let root = env::home_dir().unwrap();
+/// let mut handler = ServerHandler::<SpaMode>::new(&root);
+/// handler.with_fallback("app/index.html");
+/// ```
+pub struct SpaMode;
+
 pub trait Handler {
     fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error>;
 }
 
+impl<F> Handler for F where F: Fn(&mut Request, &mut Response) -> Result<(), io::Error> {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        self(req, res)
+    }
+}
+
+/// Behaviour that runs around a handler
+///
+/// The middleware decides when to call `next`, so it can act before and
+/// after the rest of the chain, or send its own response without calling it.
+/// Headers can only be added before the response is started, so anything that
+/// changes them must do so before calling `next`
+pub trait Middleware {
+    fn handle(&self, req: &mut Request, res: &mut Response, next: &Handler) -> Result<(), io::Error>;
+}
+
+impl<F> Middleware for F where F: Fn(&mut Request, &mut Response, &Handler) -> Result<(), io::Error> {
+    fn handle(&self, req: &mut Request, res: &mut Response, next: &Handler) -> Result<(), io::Error> {
+        self(req, res, next)
+    }
+}
+
+/// Handler that passes requests through a list of middleware before the wrapped handler
+///
+/// Middleware runs in the order it was added, the first one being the outermost.
+///
+/// #Examples
+///
+/// ```
+/// use std::env;
+/// use std::io::{self, Write};
+/// use http_server::{Request, Response};
+/// use http_server::handler::{Chain, Handler, ServerHandler, FileMode};
+///
+/// let root = env::home_dir().unwrap();
+/// let mut chain = Chain::new(Box::new(ServerHandler::<FileMode>::new(&root)));
+///
+/// chain.with_middleware(|req: &mut Request, res: &mut Response, next: &Handler| {
+///     println!("{} {}", req.method(), req.path());
+///     next.handle_request(req, res)
+/// });
+///
+/// chain.with_middleware(|req: &mut Request, res: &mut Response, next: &Handler| {
+///     if req.headers().has("Authorization") {
+///         return next.handle_request(req, res);
+///     }
+///
+///     res.with_status(401, "Unauthorized");
+///     res.start(|res| {
+///         try!(res.write_all("401 - Unauthorized".as_bytes()));
+///         Ok(())
+///     })
+/// });
+/// ```
+pub struct Chain {
+    handler: Box<Handler + Send + Sync>,
+    middleware: Vec<Box<Middleware + Send + Sync>>,
+}
+
+impl Chain {
+    pub fn new(handler: Box<Handler + Send + Sync>) -> Chain {
+        Chain {
+            handler: handler,
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn with_middleware<M>(&mut self, middleware: M) -> &mut Self
+            where M: Middleware + Send + Sync + 'static {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        let next = Next {
+            middleware: &self.middleware,
+            handler: &*self.handler,
+        };
+
+        next.handle_request(req, res)
+    }
+}
+
+/// The rest of a chain, as seen by a middleware
+struct Next<'a> {
+    middleware: &'a [Box<Middleware + Send + Sync>],
+    handler: &'a (Handler + Send + Sync),
+}
+
+impl<'a> Handler for Next<'a> {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => {
+                let next = Next {
+                    middleware: rest,
+                    handler: self.handler,
+                };
+
+                middleware.handle(req, res, &next)
+            }
+            None => self.handler.handle_request(req, res),
+        }
+    }
+}
+
+/// Resolve the components of a request path to a path under `root`
+///
+/// Components are checked one by one, so a request cannot leave the root:
+/// `..`, absolute components and components with a NUL byte are refused
+/// with `ErrorKind::PermissionDenied`, as are symbolic links not allowed by
+/// `symlinks`. Empty and `.` components are ignored.
+///
+/// #Examples
+///
+/// ```
+/// use std::io::ErrorKind;
+/// use std::path::Path;
+/// use http_server::handler::{resolve_path, SymlinkPolicy};
+///
+/// let root = Path::new("/var/www");
+/// let policy = SymlinkPolicy::Follow;
+///
+/// let path = resolve_path(root, &["css", ".", "style.css"], policy).unwrap();
+/// assert_eq!(path, Path::new("/var/www/css/style.css"));
+///
+/// for attack in &[vec!["..", "..", "etc", "passwd"],
+///                 vec!["css", "..", "..", "..", "etc", "passwd"],
+///                 vec!["/etc/passwd"],
+///                 vec!["style.css\0", "..", "..", "etc", "passwd"]] {
+///     let error = resolve_path(root, attack, policy).unwrap_err();
+///     assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+/// }
+/// ```
+///
+/// Links pointing outside the root are only followed with `SymlinkPolicy::Follow`
+///
+/// ```
+/// use std::env;
+/// use std::fs;
+/// use std::io::ErrorKind;
+/// use std::os::unix::fs::symlink;
+/// use http_server::handler::{resolve_path, SymlinkPolicy};
+///
+/// let root = env::temp_dir().join("http-server-resolve-path-doctest");
+/// let _ = fs::remove_dir_all(&root);
+/// fs::create_dir_all(root.join("docs")).unwrap();
+/// symlink("/etc/passwd", root.join("passwd")).unwrap();
+/// symlink(root.join("docs"), root.join("linked-docs")).unwrap();
+///
+/// let path = &["passwd"];
+/// assert!(resolve_path(&root, path, SymlinkPolicy::Follow).is_ok());
+///
+/// let error = resolve_path(&root, path, SymlinkPolicy::FollowWithinRoot).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+///
+/// let error = resolve_path(&root, path, SymlinkPolicy::Deny).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+///
+/// let path = &["linked-docs"];
+/// assert!(resolve_path(&root, path, SymlinkPolicy::FollowWithinRoot).is_ok());
+/// assert!(resolve_path(&root, path, SymlinkPolicy::Deny).is_err());
+///
+/// fs::remove_dir_all(&root).unwrap();
+/// ```
+pub fn resolve_path(root: &Path, components: &[&str], symlinks: SymlinkPolicy) -> Result<PathBuf, io::Error> {
+    let mut resource = root.to_path_buf();
+
+    for component in components {
+        if component.is_empty() || *component == "." {
+            continue;
+        }
+
+        let mut parts = Path::new(component).components();
+        let is_normal = match (parts.next(), parts.next()) {
+            (Some(Component::Normal(_)), None) => true,
+            _ => false,
+        };
+
+        if !is_normal || component.contains('\0') {
+            let message = format!("Invalid path component: '{}'", component);
+            return Err(io::Error::new(ErrorKind::PermissionDenied, message));
+        }
+
+        resource.push(component);
+
+        if symlinks == SymlinkPolicy::Deny {
+            let metadata = try!(fs::symlink_metadata(&resource));
+            if metadata.file_type().is_symlink() {
+                return Err(io::Error::new(ErrorKind::PermissionDenied, "Symbolic links are not allowed"));
+            }
+        }
+    }
+
+    if symlinks == SymlinkPolicy::FollowWithinRoot {
+        let canonical_root = try!(fs::canonicalize(root));
+        let canonical = try!(fs::canonicalize(&resource));
+
+        if !canonical.starts_with(&canonical_root) {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "Path is outside of the server root"));
+        }
+    }
+
+    Ok(resource)
+}
+
+/// How `ServerHandler` treats symbolic links found under its root
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Follow every link, even if it points outside the root
+    Follow,
+    /// Follow links as long as the target is inside the root
+    FollowWithinRoot,
+    /// Refuse to serve any path that goes through a link
+    Deny,
+}
+
 #[derive(Debug)]
 pub struct ServerHandler<M: Any> {
     root: PathBuf,
     mimetypes: Types,
+    show_hidden: bool,
+    symlinks: SymlinkPolicy,
+    weak_etags: bool,
+    precompressed: bool,
+    index_files: Vec<String>,
+    fallback: String,
     _kind: PhantomData<M>,
 }
 
@@ -36,25 +288,245 @@ impl<M: Any> ServerHandler<M> {
         ServerHandler {
             root: root.to_owned(),
             mimetypes: mimetypes,
+            show_hidden: false,
+            symlinks: SymlinkPolicy::FollowWithinRoot,
+            weak_etags: false,
+            precompressed: false,
+            index_files: vec!["index.html".to_owned(), "index.htm".to_owned()],
+            fallback: "index.html".to_owned(),
             _kind: PhantomData
         }
     }
 
-    fn get_resource_and_metadata(&self, req: &Request) -> Result<(PathBuf, Metadata), io::Error> {
-        let mut resource = Path::new(&self.root).to_path_buf();
+    /// Whether directory listings include files whose name starts with a dot
+    pub fn with_hidden_files(&mut self, show: bool) -> &mut Self {
+        self.show_hidden = show;
+        self
+    }
 
-        for p in req.path_components().iter() {
-            resource = resource.join(p);
-        }
+    /// Set how symbolic links under the root are treated
+    ///
+    /// The default is `SymlinkPolicy::FollowWithinRoot`
+    pub fn with_symlinks(&mut self, policy: SymlinkPolicy) -> &mut Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Whether the `ETag` sent for files is weak
+    ///
+    /// ETags are derived from the file size and modification time. They are
+    /// strong by default, which assumes a file is not changed twice within the
+    /// resolution of the file system timestamps
+    pub fn with_weak_etags(&mut self, weak: bool) -> &mut Self {
+        self.weak_etags = weak;
+        self
+    }
+
+    /// Whether to look for precompressed copies of the files
+    ///
+    /// When enabled, a request for `app.js` from a client that accepts it is
+    /// answered with `app.js.br` or `app.js.gz`, if they exist next to the
+    /// file, with the mime type of `app.js` and the matching `Content-Encoding`
+    pub fn with_precompressed(&mut self, enabled: bool) -> &mut Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Set the files served when a directory is requested, in order of preference
+    ///
+    /// The default is `index.html` and `index.htm`. An empty list disables them
+    pub fn with_index_files(&mut self, names: &[&str]) -> &mut Self {
+        self.index_files = names.iter().map(|name| (*name).to_owned()).collect();
+        self
+    }
+
+    /// Set the file served by `SpaMode` for unknown pages, relative to the root
+    ///
+    /// The default is `index.html`
+    pub fn with_fallback(&mut self, path: &str) -> &mut Self {
+        self.fallback = path.to_owned();
+        self
+    }
+
+    fn get_resource_and_metadata(&self, req: &Request) -> Result<(PathBuf, Metadata), io::Error> {
+        let resource = try!(resolve_path(&self.root, &req.path_components(), self.symlinks));
 
         let metadata = try!(fs::metadata(&resource));
 
         Ok((resource, metadata))
     }
 
-    fn send_file(&self, resource: &Path, metadata: &Metadata, res: &mut Response) -> Result<(), io::Error> {
+    /// The first index file found in the requested directory
+    fn get_index(&self, req: &Request) -> Option<(PathBuf, Metadata)> {
+        let components = req.path_components();
+
+        for name in self.index_files.iter() {
+            let mut index = components.clone();
+            index.push(name);
+
+            let path = match resolve_path(&self.root, &index, self.symlinks) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+
+            if let Ok(metadata) = fs::metadata(&path) {
+                if metadata.is_file() {
+                    return Some((path, metadata));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The precompressed copy of `resource` to send, and its content coding
+    ///
+    /// Adds `Vary: Accept-Encoding` to the response when the file has any
+    /// precompressed copy, since the body then depends on that header
+    fn precompressed_variant(&self, req: &Request, resource: &Path, res: &mut Response) -> Option<(PathBuf, Metadata, &'static str)> {
+        if !self.precompressed {
+            return None;
+        }
+
+        // the resource was resolved under the root, so its copies are
+        // resolved the same way, from the components below the root
+        let relative = match resource.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return None,
+        };
+        let components: Vec<&str> = relative.components()
+            .filter_map(|component| component.as_os_str().to_str())
+            .collect();
+        let (last, parent) = match components.split_last() {
+            Some((last, parent)) => (last, parent),
+            None => return None,
+        };
+
+        let mut variants = Vec::new();
+        for &(coding, suffix) in PRECOMPRESSED {
+            let name = format!("{}{}", last, suffix);
+            let mut sidecar = parent.to_vec();
+            sidecar.push(&name);
+
+            let path = match resolve_path(&self.root, &sidecar, self.symlinks) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+
+            if let Ok(metadata) = fs::metadata(&path) {
+                if metadata.is_file() {
+                    variants.push((path, metadata, coding));
+                }
+            }
+        }
+
+        if variants.is_empty() {
+            return None;
+        }
+
+        res.with_header("Vary", "Accept-Encoding");
+
+        let accept = match req.typed_header::<AcceptEncoding>() {
+            Ok(Some(accept)) => accept,
+            _ => return None,
+        };
+
+        // the plain file only competes when the client gives it a quality
+        let mut available: Vec<&str> = variants.iter().map(|&(_, _, coding)| coding).collect();
+        if accept.0.iter().any(|item| item.value == "identity") {
+            available.push("identity");
+        }
+
+        let coding = match accept.negotiate(&available) {
+            Some(coding) => coding,
+            None => return None,
+        };
+
+        variants.into_iter().find(|&(_, _, c)| c == coding)
+    }
+
+    fn etag(&self, metadata: &Metadata) -> String {
+        let modified = modified_time(metadata);
+        let tag = format!("\"{:x}-{:x}.{:x}\"", metadata.len(), modified.sec, modified.nsec);
+
+        if self.weak_etags {
+            format!("W/{}", tag)
+        } else {
+            tag
+        }
+    }
+
+    /// Evaluate the conditional request headers as described in RFC 7232
+    ///
+    /// Returns the status to send instead of the file, if any
+    fn check_preconditions(&self, req: &Request, etag: &str, modified: Timespec) -> Option<(i32, &'static str)> {
+        let is_get = req.method() == "GET" || req.method() == "HEAD";
+
+        let if_match = req.headers().get_list("If-Match");
+        let if_none_match = req.headers().get_list("If-None-Match");
+
+        if !if_match.is_empty() {
+            if !etag_matches(&if_match, etag, true) {
+                return Some((412, "Precondition Failed"));
+            }
+        } else if let Some(date) = header_date(req, "If-Unmodified-Since") {
+            if modified.sec > date.sec {
+                return Some((412, "Precondition Failed"));
+            }
+        }
+
+        if !if_none_match.is_empty() {
+            if etag_matches(&if_none_match, etag, false) {
+                return Some(if is_get { (304, "Not Modified") } else { (412, "Precondition Failed") });
+            }
+        } else if is_get {
+            if let Some(date) = header_date(req, "If-Modified-Since") {
+                if modified.sec <= date.sec {
+                    return Some((304, "Not Modified"));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn send_file(&self, req: &Request, resource: &Path, metadata: &Metadata, res: &mut Response) -> Result<(), io::Error> {
+        let mime = self.mimetypes.mime_for_path(resource);
+
+        let variant = self.precompressed_variant(req, resource, res);
+        let (resource, metadata) = match variant {
+            Some((ref path, ref metadata, coding)) => {
+                res.with_header("Content-Encoding", coding);
+                (path.as_path(), metadata)
+            }
+            None => (resource, metadata),
+        };
+
+        let etag = self.etag(metadata);
+        let modified = modified_time(metadata);
+
+        res.with_header("ETag", &etag)
+            .with_header("Last-Modified", &format_http_date(modified));
+
+        if let Some((status, description)) = self.check_preconditions(req, &etag, modified) {
+            if status != 304 {
+                return res.send_error(status, description);
+            }
+
+            // describe the response it stands for, to be compressed the same way
+            res.with_status(status, description)
+                .with_header("Content-Type", mime)
+                .with_header("Content-Length", &metadata.len().to_string());
+            return res.start(|_| Ok(()));
+        }
+
         let mut f = try!(File::open(&resource));
-        let mime = self.mimetypes.mime_for_path(Path::new(&resource));
+
+        res.with_header("Accept-Ranges", "bytes");
+
+        if let Some(ranges) = self.requested_ranges(req, &etag, modified, metadata.len()) {
+            return self.send_ranges(&mut f, mime, metadata.len(), &ranges, res);
+        }
 
         res.with_header("Content-Type", mime)
             .with_header("Content-Length", &metadata.len().to_string());
@@ -66,103 +538,415 @@ impl<M: Any> ServerHandler<M> {
         })
     }
 
-    fn send_not_found(&self, res: &mut Response) -> Result<(), io::Error> {
-        res.with_status(404, "Not Found");
-        res.start(|res| {
-            try!(res.write("404 - Not Found".as_bytes()));
-            try!(res.flush());
-            Ok(())
-        })
+    /// Byte ranges asked with the `Range` header, if they should be honoured
+    ///
+    /// The header is ignored when it is invalid or when `If-Range` does not
+    /// match the current file. An empty list means no range can be satisfied
+    fn requested_ranges(&self, req: &Request, etag: &str, modified: Timespec, len: u64) -> Option<Vec<(u64, u64)>> {
+        if req.method() != "GET" {
+            return None;
+        }
+
+        // an invalid header is ignored, as if it was not sent
+        let range = match req.typed_header::<Range>() {
+            Ok(Some(range)) => range,
+            _ => return None,
+        };
+
+        if let Some(if_range) = req.headers().get("If-Range") {
+            let matches = if if_range.starts_with('"') {
+                !etag.starts_with("W/") && if_range == etag
+            } else {
+                parse_http_date(&if_range).map_or(false, |date| date.sec == modified.sec)
+            };
+
+            if !matches {
+                return None;
+            }
+        }
+
+        Some(range.satisfiable(len))
     }
 
-    fn send_error(&self, res: &mut Response, status: i32, description: &str) -> Result<(), io::Error> {
-        res.with_status(status, description);
+    fn send_ranges(&self, f: &mut File, mime: &str, len: u64, ranges: &[(u64, u64)], res: &mut Response) -> Result<(), io::Error> {
+        if ranges.is_empty() {
+            res.with_header("Content-Range", &format!("bytes */{}", len));
+            return res.send_error(416, "Range Not Satisfiable");
+        }
+
+        res.with_status(206, "Partial Content");
+
+        if ranges.len() == 1 {
+            let (first, last) = ranges[0];
+
+            res.with_header("Content-Type", mime)
+                .with_header("Content-Range", &format!("bytes {}-{}/{}", first, last, len))
+                .with_header("Content-Length", &(last - first + 1).to_string());
+
+            return res.start(|res| {
+                try!(copy_range(f, res, first, last));
+                try!(res.flush());
+                Ok(())
+            });
+        }
+
+        let now = time::get_time();
+        let boundary = format!("{:x}{:x}{:x}", now.sec, now.nsec, len);
+
+        let part_headers: Vec<String> = ranges.iter().map(|&(first, last)| {
+            format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, mime, first, last, len)
+        }).collect();
+        let closing = format!("\r\n--{}--\r\n", boundary);
+
+        let content_length = ranges.iter().zip(part_headers.iter())
+            .fold(closing.len() as u64, |total, (&(first, last), headers)| {
+                total + headers.len() as u64 + last - first + 1
+            });
+
+        res.with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+            .with_header("Content-Length", &content_length.to_string());
+
         res.start(|res| {
-            try!(res.write(format!("{} - {}", status, description).as_bytes()));
+            for (&(first, last), headers) in ranges.iter().zip(part_headers.iter()) {
+                try!(res.write_all(headers.as_bytes()));
+                try!(copy_range(f, res, first, last));
+            }
+            try!(res.write_all(closing.as_bytes()));
             try!(res.flush());
             Ok(())
         })
     }
+
+    /// Redirect a directory requested without the trailing slash, so relative links work
+    fn send_directory_redirect(&self, req: &Request, res: &mut Response) -> Result<(), io::Error> {
+        // a location starting with `//`, or `/\` for browsers, would point to another host
+        let path = format!("/{}/", req.path().trim_left_matches('/'));
+        let mut location = perc_enc::percent_encode(path.as_bytes(), perc_enc::DEFAULT_ENCODE_SET)
+            .replace('\\', "%5C");
+
+        if let Some(query_string) = req.query().as_ref().and_then(|q| q.query_string()) {
+            location.push('?');
+            location.push_str(query_string);
+        }
+
+        res.with_header("Location", &location);
+        res.send_error(301, "Moved Permanently")
+    }
+
+    fn send_options(&self, res: &mut Response) -> Result<(), io::Error> {
+        res.with_header("Allow", ALLOWED_METHODS)
+            .with_header("Content-Length", "0");
+        res.start(|_| Ok(()))
+    }
+
+    fn send_method_not_allowed(&self, res: &mut Response) -> Result<(), io::Error> {
+        res.with_header("Allow", ALLOWED_METHODS);
+        res.send_error(405, "Method Not Allowed")
+    }
 }
 
-impl Handler for ServerHandler<FileMode> {
-    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+/// What `ServerHandler::serve` did with a request
+enum Served {
+    /// A response was sent
+    Sent(Result<(), io::Error>),
+    /// There is no file or directory index at the path
+    NotFound,
+    /// A directory without index, to be listed
+    Directory(PathBuf),
+}
+
+impl<M: Any> ServerHandler<M> {
+    /// Answer the request with the requested file or the index of the requested directory
+    ///
+    /// Other methods, errors and directories requested without the trailing
+    /// slash are answered too. Directories without index are only redirected
+    /// and returned when `listing` is set, otherwise they are not found
+    fn serve(&self, req: &Request, res: &mut Response, listing: bool) -> Served {
+        match req.method() {
+            "GET" | "HEAD" => {},
+            "OPTIONS" => return Served::Sent(self.send_options(res)),
+            _ => return Served::Sent(self.send_method_not_allowed(res)),
+        }
+
         let (resource, metadata) = match self.get_resource_and_metadata(req) {
             Ok(result) => result,
             Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    return self.send_not_found(res);
-                } else {
-                    return self.send_error(res, 500, "Internal Server Error");
-                }
+                return match e.kind() {
+                    ErrorKind::NotFound => Served::NotFound,
+                    ErrorKind::PermissionDenied => Served::Sent(res.send_error(403, "Forbidden")),
+                    _ => Served::Sent(res.send_error(500, "Internal Server Error")),
+                };
             }
         };
 
-        if !metadata.is_file() {
-            return self.send_not_found(res);
+        if metadata.is_file() {
+            return Served::Sent(self.send_file(req, &resource, &metadata, res));
+        }
+
+        if !metadata.is_dir() {
+            return Served::NotFound;
+        }
+
+        let index = self.get_index(req);
+        if index.is_none() && !listing {
+            return Served::NotFound;
         }
 
-        self.send_file(&resource, &metadata, res)
+        if !req.path().ends_with('/') {
+            return Served::Sent(self.send_directory_redirect(req, res));
+        }
+
+        match index {
+            Some((index, metadata)) => Served::Sent(self.send_file(req, &index, &metadata, res)),
+            None => Served::Directory(resource),
+        }
     }
 }
 
-impl Handler for ServerHandler<DirectoryMode> {
+impl Handler for ServerHandler<FileMode> {
     fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
-        let (resource, metadata) = match self.get_resource_and_metadata(req) {
-            Ok(result) => result,
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    return self.send_not_found(res);
-                } else {
-                    return self.send_error(res, 500, "Internal Server Error");
-                }
-            }
+        match self.serve(req, res, false) {
+            Served::Sent(result) => result,
+            _ => res.send_error(404, "Not Found"),
+        }
+    }
+}
+
+impl ServerHandler<SpaMode> {
+    /// Whether a missing path is a page of the application rather than a missing asset
+    fn wants_fallback(&self, req: &Request) -> bool {
+        let components = req.path_components();
+        let name = components.last().map_or("", |name| *name);
+        if name.contains('.') {
+            return false;
+        }
+
+        match req.typed_header::<Accept>() {
+            Ok(Some(accept)) => accept.quality("text/html") > 0.0,
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn send_fallback(&self, req: &Request, res: &mut Response) -> Result<(), io::Error> {
+        let components: Vec<&str> = self.fallback.split('/').collect();
+
+        let result = resolve_path(&self.root, &components, self.symlinks)
+            .and_then(|path| fs::metadata(&path).map(|metadata| (path, metadata)));
+
+        match result {
+            Ok((ref path, ref metadata)) if metadata.is_file() => self.send_file(req, path, metadata, res),
+            Ok(_) => res.send_error(404, "Not Found"),
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => res.send_error(404, "Not Found"),
+                _ => res.send_error(500, "Internal Server Error"),
+            },
+        }
+    }
+}
+
+impl Handler for ServerHandler<SpaMode> {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        if let Served::Sent(result) = self.serve(req, res, false) {
+            return result;
+        }
+
+        // whether the page is sent depends on what the client accepts
+        res.with_header("Vary", "Accept");
+
+        if !self.wants_fallback(req) {
+            return res.send_error(404, "Not Found");
+        }
+
+        self.send_fallback(req, res)
+    }
+}
+
+impl ServerHandler<DirectoryMode> {
+    fn send_listing(&self, req: &Request, dir: &Path, res: &mut Response) -> Result<(), io::Error> {
+        let mut entries = match self.read_entries(dir) {
+            Ok(entries) => entries,
+            Err(_) => return res.send_error(500, "Internal Server Error"),
         };
 
-        if metadata.is_file() {
-            return self.send_file(&resource, &metadata, res);
+        let sort = req.query().as_ref()
+            .and_then(|q| q.get_first("sort"))
+            .unwrap_or("name");
+
+        entries.sort_by(|a, b| {
+            let ordering = match sort {
+                "size" => a.size.cmp(&b.size),
+                "mtime" => a.modified.cmp(&b.modified),
+                _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            };
+            // directories always come first
+            b.is_dir.cmp(&a.is_dir).then(ordering)
+        });
+
+        // an invalid header is ignored, as if it was not sent
+        let wants_json = match req.typed_header::<Accept>() {
+            Ok(Some(accept)) => accept.negotiate(&["text/html", "application/json"]) == Some("application/json"),
+            _ => false,
+        };
+
+        // the format of the listing depends on what the client accepts
+        res.with_header("Vary", "Accept");
+
+        if wants_json {
+            return send_listing_json(&entries, res);
         }
 
-        let output = Command::new("ls")
-            .arg(&resource)
-            .output()
-            .unwrap_or_else(|e| panic!(format!("Failed to list dir: {}", e)));
+        send_listing_html(req.path(), &entries, res)
+    }
+}
 
-        let s: String;
-        if output.status.success() {
-            s = String::from_utf8_lossy(&output.stdout).as_ref().to_owned();
-        } else {
-            s = String::from_utf8_lossy(&output.stderr).as_ref().to_owned();
-            panic!("rustc failed and stderr was:\n{}", s);
+impl Handler for ServerHandler<DirectoryMode> {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        match self.serve(req, res, true) {
+            Served::Sent(result) => result,
+            Served::NotFound => res.send_error(404, "Not Found"),
+            Served::Directory(dir) => self.send_listing(req, &dir, res),
         }
+    }
+}
 
-        res.with_header("Content-Type", "text/html; charset=utf-8");
+fn modified_time(metadata: &Metadata) -> Timespec {
+    metadata.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(Timespec::new(0, 0), |d| Timespec::new(d.as_secs() as i64, d.subsec_nanos() as i32))
+}
 
-        res.start(|res| {
-            try!(res.write("<html><body><ul>".as_bytes()));
-            for name in s.split('\n') {
-                if name.len() == 0 { continue }
-                let mut name = name.to_owned();
+fn header_date(req: &Request, name: &str) -> Option<Timespec> {
+    req.headers().get(name).and_then(parse_http_date)
+}
 
-                let metadata = try!(fs::metadata(Path::new(&resource).join(&name)));
+/// Whether `etag` is in a list of entity tags from `If-Match` or `If-None-Match`
+///
+/// The strong comparison is used for `If-Match`, where weak tags never match
+fn etag_matches(list: &[&str], etag: &str, strong: bool) -> bool {
+    let opaque = |tag: &str| -> String { tag.trim_left_matches("W/").to_owned() };
 
-                if metadata.is_dir() {
-                    name = format!("{}/", name);
-                }
+    list.iter().any(|tag| {
+        if *tag == "*" {
+            return true;
+        }
+
+        if strong {
+            !tag.starts_with("W/") && !etag.starts_with("W/") && *tag == etag
+        } else {
+            opaque(tag) == opaque(etag)
+        }
+    })
+}
 
-                let mut path = req.path().to_owned();
-                path.push_str(&name);
-                let path = perc_enc::percent_encode(
-                    path.as_bytes(),
-                    perc_enc::DEFAULT_ENCODE_SET
-                );
+fn copy_range<W: Write>(f: &mut File, writer: &mut W, first: u64, last: u64) -> Result<(), io::Error> {
+    try!(f.seek(SeekFrom::Start(first)));
+    try!(io::copy(&mut f.take(last - first + 1), writer));
+    Ok(())
+}
 
-                try!(res.write(format!("<li><a href=\"{0}\">{1}</a></li>", path, name).as_bytes()));
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+impl<M: Any> ServerHandler<M> {
+    fn read_entries(&self, dir: &Path) -> Result<Vec<DirEntry>, io::Error> {
+        let mut entries = Vec::new();
+
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            if !self.show_hidden && name.starts_with('.') {
+                continue;
             }
-            try!(res.write("</ul></body></html>".as_bytes()));
-            try!(res.flush());
 
-            Ok(())
-        })
+            // fall back to the link itself when a symlink is broken
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => try!(entry.metadata()),
+            };
+
+            let modified = modified_time(&metadata).sec as u64;
+
+            entries.push(DirEntry {
+                name: name,
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: modified,
+            });
+        }
+
+        Ok(entries)
     }
 }
+
+fn send_listing_html(path: &str, entries: &[DirEntry], res: &mut Response) -> Result<(), io::Error> {
+    res.with_header("Content-Type", "text/html; charset=utf-8");
+
+    res.start(|res| {
+        try!(write!(res, "<html><head><title>Index of {0}</title></head><body><h1>Index of {0}</h1>",
+                    escape_html(path)));
+        try!(res.write_all("<table><tr><th><a href=\"?sort=name\">Name</a></th>".as_bytes()));
+        try!(res.write_all("<th><a href=\"?sort=size\">Size</a></th>".as_bytes()));
+        try!(res.write_all("<th><a href=\"?sort=mtime\">Modified</a></th></tr>".as_bytes()));
+
+        let trimmed = path.trim_right_matches('/');
+        if !trimmed.is_empty() {
+            let parent = &trimmed[..trimmed.rfind('/').unwrap_or(0) + 1];
+            let parent = perc_enc::percent_encode(parent.as_bytes(), perc_enc::DEFAULT_ENCODE_SET);
+            try!(write!(res, "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>", parent));
+        }
+
+        for entry in entries {
+            let mut name = entry.name.clone();
+            if entry.is_dir {
+                name.push('/');
+            }
+
+            let mut href = path.to_owned();
+            href.push_str(&name);
+            let href = perc_enc::percent_encode(href.as_bytes(), perc_enc::DEFAULT_ENCODE_SET);
+
+            let size = if entry.is_dir { "-".to_owned() } else { entry.size.to_string() };
+            let modified = time::at_utc(Timespec::new(entry.modified as i64, 0));
+
+            try!(write!(res, "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+                        escape_html(&href), escape_html(&name), size,
+                        modified.strftime("%Y-%m-%d %H:%M:%S").unwrap()));
+        }
+
+        try!(res.write_all("</table></body></html>".as_bytes()));
+        try!(res.flush());
+
+        Ok(())
+    })
+}
+
+fn send_listing_json(entries: &[DirEntry], res: &mut Response) -> Result<(), io::Error> {
+    res.with_header("Content-Type", "application/json");
+
+    res.start(|res| {
+        try!(res.write_all(b"["));
+
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                try!(res.write_all(b","));
+            }
+
+            try!(write!(res, "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                        escape_json(&entry.name),
+                        if entry.is_dir { "directory" } else { "file" },
+                        entry.size, entry.modified));
+        }
+
+        try!(res.write_all(b"]"));
+        try!(res.flush());
+
+        Ok(())
+    })
+}
@@ -1,66 +1,227 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::slice;
 
-#[derive(Clone, Debug)]
+use time::{self, Timespec};
+
+use ::parser::ParseError;
+
+/// HTTP header fields
+///
+/// Names are compared ignoring case, as described in RFC 7230, but are kept
+/// as given and sent in the order they were added
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::Headers;
+///
+/// let mut headers = Headers::new();
+/// headers.set("Content-Type", "text/plain");
+/// headers.append("Set-Cookie", "a=1");
+/// headers.append("Set-Cookie", "b=2");
+///
+/// assert_eq!(headers.get("content-type"), Some("text/plain"));
+/// assert_eq!(headers.get_all("SET-COOKIE"), vec!["a=1", "b=2"]);
+/// assert_eq!(headers.to_string(), "Content-Type: text/plain\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n");
+/// ```
+#[derive(Clone, Debug, Default)]
 pub struct Headers {
-    data: HashMap<String, Vec<String>>,
+    data: Vec<(String, String)>,
 }
 
-#[allow(dead_code)]
 impl Headers {
     pub fn new() -> Self {
         Headers {
-            data: HashMap::<String, Vec<String>>::new(),
+            data: Vec::new(),
         }
     }
 
-    pub fn with_data(data: HashMap<String, Vec<String>>) -> Self {
-        Headers {
-            data: data,
+    /// Parse a header field line, like `Content-Type: text/html`, and add it
+    pub fn parse(&mut self, header: &str) -> Result<&mut Self, ParseError> {
+        let header = header.trim_right_matches(|c| c == '\r' || c == '\n');
+
+        match parse_field(header) {
+            Some((name, value)) => {
+                self.append(name, value);
+                Ok(self)
+            }
+            None => Err(ParseError::InvalidHeader(header.to_owned())),
         }
     }
 
-    pub fn parse(&mut self, header: &str) -> &Self {
-        let header: Vec<_> = header.split(": ").collect();
-        let name = header[0];
+    /// Get the first value of the header `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// Get every value of the header `name`, in the order they were added
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.iter().filter(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value).collect()
+    }
 
-        for value in header[1].split(',') {
-            let mut vec = self.data.entry(name.trim().to_owned()).or_insert(Vec::<String>::new());
-            vec.push(value.trim().to_owned());
+    /// Get the elements of the comma separated list in the header `name`
+    ///
+    /// The values of every field with that name are combined, as described
+    /// in RFC 7230. See `split_list`
+    ///
+    /// #Examples
+    ///
+    /// ```
+    /// use http_server::headers::Headers;
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.append("If-None-Match", r#""a,b", W/"c""#);
+    /// headers.append("If-None-Match", r#""d""#);
+    ///
+    /// assert_eq!(headers.get_list("If-None-Match"), vec![r#""a,b""#, r#"W/"c""#, r#""d""#]);
+    /// ```
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name).into_iter().flat_map(split_list).collect()
+    }
+
+    /// Set the header `name` to `value`, replacing any previous value
+    ///
+    /// The header keeps the position of its first value, if it had any
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.data.iter().position(|&(ref n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(index) => {
+                self.data[index] = (name.to_owned(), value.to_owned());
+                let rest = self.data.split_off(index + 1);
+                self.data.extend(rest.into_iter().filter(|&(ref n, _)| !n.eq_ignore_ascii_case(name)));
+            }
+            None => self.append(name, value),
         }
+    }
 
-        self
+    /// Add a value to the header `name`, keeping the previous ones
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.data.push((name.to_owned(), value.to_owned()));
     }
 
-    pub fn insert(&mut self, name: &str, value: &str) {
-        let mut vec = self.data.entry(name.to_owned()).or_insert(Vec::<String>::new());
-        vec.push(value.to_owned());
-    }
-
-    pub fn find(&self, key: &str) -> Option<Vec<&str>> {
-        match self.data.get(key) {
-            Some(vec) => {
-                if vec.is_empty() {
-                    None
-                } else {
-                    let vec: Vec<&str> = vec.iter().map(|x| x.as_ref()).collect();
-                    Some(vec)
-                }
+    /// Remove every value of the header `name`
+    ///
+    /// Returns whether the header was present
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.data.len();
+        self.data.retain(|&(ref n, _)| !n.eq_ignore_ascii_case(name));
+        self.data.len() != len
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Number of header values
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get the header `H`, or `None` if it is missing
+    ///
+    /// #Examples
+    ///
+    /// ```
+    /// use http_server::headers::{Headers, ContentLength};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set_typed(&ContentLength(10));
+    /// assert_eq!(headers.typed::<ContentLength>(), Ok(Some(ContentLength(10))));
+    ///
+    /// headers.set("Content-Length", "ten");
+    /// assert!(headers.typed::<ContentLength>().is_err());
+    /// ```
+    pub fn typed<H: Header>(&self) -> Result<Option<H>, HeaderError> {
+        let values = self.get_all(H::name());
+        if values.is_empty() {
+            return Ok(None);
+        }
+
+        let value = values.join(", ");
+        match H::parse_value(&value) {
+            Some(header) => Ok(Some(header)),
+            None => Err(HeaderError::new(H::name(), &value)),
+        }
+    }
+
+    /// Set the header `H`, replacing any previous value
+    pub fn set_typed<H: Header>(&mut self, header: &H) {
+        self.set(H::name(), &header.to_value());
+    }
+
+    /// Iterate over the names and values, in the order they were added
+    pub fn iter(&self) -> Iter {
+        Iter { inner: self.data.iter() }
+    }
+
+    #[deprecated(since = "0.1.0", note = "use `Headers::new` and `append`")]
+    pub fn with_data(data: HashMap<String, Vec<String>>) -> Self {
+        let mut headers = Headers::new();
+        for (name, values) in data.iter() {
+            for value in values.iter() {
+                headers.append(name, value);
             }
-            _ => None
         }
+        headers
     }
 
-    pub fn has(&self, key: &str) -> bool {
-        self.data.contains_key(key)
+    #[deprecated(since = "0.1.0", note = "use `append`")]
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.append(name, value);
+    }
+
+    /// The values of the header `name`, split at the commas
+    #[deprecated(since = "0.1.0", note = "use `get_list`, or `get_all` for the values as received")]
+    pub fn find(&self, name: &str) -> Option<Vec<&str>> {
+        let values = self.get_list(name);
+        if values.is_empty() { None } else { Some(values) }
     }
 
+    /// Every header name with its values, names compared without case
+    #[deprecated(since = "0.1.0", note = "use `iter`")]
     pub fn all(&self) -> Vec<(&str, Vec<&str>)> {
-        let vec = self.data.iter().map(|(key, values)| {
-            let header_vec: Vec<&str> = values.iter().map(|x| x.as_ref()).collect();
-            (key.as_ref(), header_vec)
-        }).collect();
+        let mut all: Vec<(&str, Vec<&str>)> = Vec::new();
+
+        for (name, value) in self.iter() {
+            match all.iter().position(|&(n, _)| n.eq_ignore_ascii_case(name)) {
+                Some(index) => all[index].1.push(value),
+                None => all.push((name, vec![value])),
+            }
+        }
+
+        all
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
 
-        vec
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterator over the names and values of `Headers`
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (String, String)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.inner.next().map(|&(ref name, ref value)| (name.as_ref(), value.as_ref()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
     }
 }
 
@@ -68,20 +229,972 @@ impl ToString for Headers {
     fn to_string(&self) -> String {
         let mut result = String::new();
 
-        for (key, vec) in &self.data {
-            let mut iter = vec.iter();
-            match iter.next() {
-                Some(i) => result.push_str(&format!("{}: {}", key, i)),
-                None => return result,
+        for (name, value) in self.iter() {
+            result.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        result
+    }
+}
+
+/// Split a header field line into its name and value
+///
+/// Whitespace around the value is removed. Returns `None` when the name is
+/// not a valid token or is followed by whitespace, and when the value has
+/// control characters. Lines continuing the previous one (obsolete line
+/// folding) start with whitespace, so they are rejected as well
+pub fn parse_field(line: &str) -> Option<(&str, &str)> {
+    let colon = match line.find(':') {
+        Some(colon) => colon,
+        None => return None,
+    };
+
+    let name = &line[..colon];
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return None;
+    }
+
+    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return None;
+    }
+
+    Some((name, value))
+}
+
+/// Split a comma separated header value into its elements
+///
+/// Commas inside quoted strings do not separate elements. Empty elements
+/// are skipped and whitespace around each element is removed
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::split_list;
+///
+/// assert_eq!(split_list("gzip, , deflate;q=0.5"), vec!["gzip", "deflate;q=0.5"]);
+/// assert_eq!(split_list(r#"a="x, \"y\"", b"#), vec![r#"a="x, \"y\"""#, "b"]);
+/// ```
+pub fn split_list(value: &str) -> Vec<&str> {
+    split_quoted(value, ',')
+}
+
+/// Split `value` on `separator` when not inside a quoted string
+fn split_quoted(value: &str, separator: char) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                items.push(&value[start..i]);
+                start = i + 1;
             }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+
+    items.into_iter()
+        .map(|item| item.trim_matches(|c| c == ' ' || c == '\t'))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Whether `b` can be part of a token, like a header name
+fn is_token_char(b: u8) -> bool {
+    match b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' => true,
+        b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    }
+}
+
+/// Format a time as an HTTP-date, like `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_http_date(date: Timespec) -> String {
+    time::at_utc(date).rfc822().to_string()
+}
+
+/// Parse an HTTP-date in any of the formats accepted by RFC 7231
+///
+/// Besides the preferred format, the obsolete RFC 850 and asctime formats
+/// are also accepted. Returns `None` if the date is invalid
+pub fn parse_http_date(date: &str) -> Option<Timespec> {
+    let date = date.trim();
+
+    if let Ok(tm) = time::strptime(date, "%a, %d %b %Y %H:%M:%S GMT") {
+        return Some(tm.to_timespec());
+    }
+
+    if let Ok(mut tm) = time::strptime(date, "%A, %d-%b-%y %H:%M:%S GMT") {
+        // two digit years more than 50 years in the future are in the past
+        if tm.tm_year < 70 {
+            tm.tm_year += 100;
+        }
+        return Some(tm.to_timespec());
+    }
+
+    if let Ok(tm) = time::strptime(date, "%a %b %e %H:%M:%S %Y") {
+        return Some(tm.to_timespec());
+    }
+
+    None
+}
 
-            for i in iter {
-                result.push_str(&format!(", {}", i));
+/// Error for a header whose value could not be parsed
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderError {
+    name: &'static str,
+    value: String,
+}
+
+impl HeaderError {
+    pub fn new(name: &'static str, value: &str) -> HeaderError {
+        HeaderError {
+            name: name,
+            value: value.to_owned(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid {} header: '{}'", self.name, self.value)
+    }
+}
+
+impl Error for HeaderError {
+    fn description(&self) -> &str {
+        "Invalid header value"
+    }
+}
+
+impl From<HeaderError> for ParseError {
+    fn from(error: HeaderError) -> ParseError {
+        ParseError::InvalidHeader(format!("{}: {}", error.name, error.value))
+    }
+}
+
+/// A header that can be read from and written to `Headers`
+///
+/// Used with `Headers::typed`, `Request::typed_header` and
+/// `Response::with_typed_header`
+pub trait Header: Sized {
+    /// Name of the header, like `Content-Type`
+    fn name() -> &'static str;
+
+    /// Parse the value of the header, returning `None` if it is invalid
+    ///
+    /// Headers sent in several fields are given as a single value,
+    /// with the values separated by commas
+    fn parse_value(value: &str) -> Option<Self>;
+
+    /// Format the header as it is sent
+    fn to_value(&self) -> String;
+}
+
+/// The `Content-Type` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, ContentType};
+///
+/// let content_type = ContentType::parse_value("Text/HTML; charset=\"UTF-8\"").unwrap();
+/// assert_eq!(content_type.mime, "text/html");
+/// assert_eq!(content_type.charset(), Some("UTF-8"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentType {
+    /// The media type, in lower case, like `text/html`
+    pub mime: String,
+    /// Parameters after the media type, with names in lower case
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn new(mime: &str) -> ContentType {
+        ContentType {
+            mime: mime.to_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with_charset(mime: &str, charset: &str) -> ContentType {
+        let mut content_type = ContentType::new(mime);
+        content_type.params.push(("charset".to_owned(), charset.to_owned()));
+        content_type
+    }
+
+    /// Value of the parameter `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_ref())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+}
+
+impl Header for ContentType {
+    fn name() -> &'static str {
+        "Content-Type"
+    }
+
+    fn parse_value(value: &str) -> Option<ContentType> {
+        let (mime, params) = match parse_media_type(value) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+
+        Some(ContentType {
+            mime: mime,
+            params: params,
+        })
+    }
+
+    fn to_value(&self) -> String {
+        format_with_params(&self.mime, &self.params)
+    }
+}
+
+/// The `Content-Length` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContentLength(pub u64);
+
+impl Header for ContentLength {
+    fn name() -> &'static str {
+        "Content-Length"
+    }
+
+    fn parse_value(value: &str) -> Option<ContentLength> {
+        if value.is_empty() || !value.bytes().all(|b| b >= b'0' && b <= b'9') {
+            return None;
+        }
+        value.parse().ok().map(ContentLength)
+    }
+
+    fn to_value(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The `Content-Disposition` header, as used in `multipart/form-data` parts
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, ContentDisposition};
+///
+/// let disposition = ContentDisposition::parse_value("form-data; name=\"file\"; filename=\"a b.txt\"").unwrap();
+/// assert_eq!(disposition.disposition, "form-data");
+/// assert_eq!(disposition.param("name"), Some("file"));
+/// assert_eq!(disposition.param("filename"), Some("a b.txt"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentDisposition {
+    /// The disposition type, in lower case, like `attachment`
+    pub disposition: String,
+    /// Parameters after the disposition type, with names in lower case
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentDisposition {
+    /// Value of the parameter `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_ref())
+    }
+}
+
+impl Header for ContentDisposition {
+    fn name() -> &'static str {
+        "Content-Disposition"
+    }
+
+    fn parse_value(value: &str) -> Option<ContentDisposition> {
+        let (disposition, params) = match parse_with_params(value) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+
+        if disposition.is_empty() || !disposition.bytes().all(is_token_char) {
+            return None;
+        }
+
+        Some(ContentDisposition {
+            disposition: disposition.to_lowercase(),
+            params: params,
+        })
+    }
+
+    fn to_value(&self) -> String {
+        format_with_params(&self.disposition, &self.params)
+    }
+}
+
+/// A value with its quality, from headers like `Accept`
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityItem {
+    /// The value without the quality, in lower case
+    pub value: String,
+    /// The quality, from 0 to 1
+    pub quality: f32,
+}
+
+/// The `Accept` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, Accept};
+///
+/// let accept = Accept::parse_value("text/html, application/json;q=0.9, */*;q=0.1").unwrap();
+/// assert_eq!(accept.quality("application/json"), 0.9);
+/// assert_eq!(accept.quality("image/png"), 0.1);
+/// assert_eq!(accept.negotiate(&["application/json", "text/html"]), Some("text/html"));
+///
+/// // parameters are compared as bytes, so they can hold any character
+/// let accept = Accept::parse_value("text/html;é, text/plain;qé").unwrap();
+/// assert_eq!(accept.quality("text/plain"), 1.0);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accept(pub Vec<QualityItem>);
+
+impl Accept {
+    /// Quality of the media type `mime`, from the most specific matching range
+    ///
+    /// Media types matching no range have quality 0
+    pub fn quality(&self, mime: &str) -> f32 {
+        let mime = mime.to_lowercase();
+        let main_type = mime.split('/').next().unwrap_or("");
+
+        let mut best: Option<(u8, f32)> = None;
+        for item in self.0.iter() {
+            let range = item.value.split(';').next().unwrap_or("").trim();
+
+            let specificity = if range == mime {
+                3
+            } else if range.ends_with("/*") && range[..range.len() - 2] == *main_type {
+                2
+            } else if range == "*/*" {
+                1
+            } else {
+                continue;
+            };
+
+            if best.map_or(true, |(s, _)| specificity > s) {
+                best = Some((specificity, item.quality));
             }
+        }
+
+        best.map_or(0.0, |(_, quality)| quality)
+    }
+
+    /// The acceptable media type from `available` with the highest quality
+    ///
+    /// Ties are resolved by the order of `available`
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        negotiate(available, |mime| self.quality(mime))
+    }
+}
+
+impl Header for Accept {
+    fn name() -> &'static str {
+        "Accept"
+    }
 
-            result.push_str("\r\n");
+    fn parse_value(value: &str) -> Option<Accept> {
+        parse_quality_list(value).map(Accept)
+    }
+
+    fn to_value(&self) -> String {
+        format_quality_list(&self.0)
+    }
+}
+
+/// The `Accept-Encoding` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, AcceptEncoding};
+///
+/// let accept = AcceptEncoding::parse_value("gzip;q=0.8, br").unwrap();
+/// assert_eq!(accept.negotiate(&["gzip", "br"]), Some("br"));
+/// assert_eq!(accept.quality("identity"), 1.0);
+/// assert_eq!(accept.quality("deflate"), 0.0);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AcceptEncoding(pub Vec<QualityItem>);
+
+impl AcceptEncoding {
+    /// Quality of the content coding `coding`
+    ///
+    /// `identity` is acceptable unless it is explicitly refused
+    pub fn quality(&self, coding: &str) -> f32 {
+        let coding = coding.to_lowercase();
+
+        if let Some(item) = self.0.iter().find(|item| item.value == coding) {
+            return item.quality;
         }
 
-        result
+        if let Some(item) = self.0.iter().find(|item| item.value == "*") {
+            return item.quality;
+        }
+
+        if coding == "identity" { 1.0 } else { 0.0 }
+    }
+
+    /// The acceptable coding from `available` with the highest quality
+    ///
+    /// Ties are resolved by the order of `available`
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        negotiate(available, |coding| self.quality(coding))
+    }
+}
+
+impl Header for AcceptEncoding {
+    fn name() -> &'static str {
+        "Accept-Encoding"
+    }
+
+    fn parse_value(value: &str) -> Option<AcceptEncoding> {
+        parse_quality_list(value).map(AcceptEncoding)
+    }
+
+    fn to_value(&self) -> String {
+        format_quality_list(&self.0)
+    }
+}
+
+/// The `Authorization` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, Authorization};
+///
+/// let auth = Authorization::parse_value("Basic YWxhZGRpbjpvcGVuc2VzYW1l").unwrap();
+/// assert_eq!(auth.basic_credentials(), Some(("aladdin".to_owned(), "opensesame".to_owned())));
+/// assert_eq!(Authorization::new_basic("aladdin", "opensesame"), auth);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Authorization {
+    pub scheme: String,
+    pub credentials: String,
+}
+
+impl Authorization {
+    /// Credentials for the `Basic` scheme
+    pub fn new_basic(user: &str, password: &str) -> Authorization {
+        Authorization {
+            scheme: "Basic".to_owned(),
+            credentials: base64_encode(format!("{}:{}", user, password).as_bytes()),
+        }
+    }
+
+    /// User and password, if the scheme is `Basic`
+    pub fn basic_credentials(&self) -> Option<(String, String)> {
+        if !self.scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = match base64_decode(&self.credentials).and_then(|d| String::from_utf8(d).ok()) {
+            Some(decoded) => decoded,
+            None => return None,
+        };
+
+        decoded.find(':').map(|colon| (decoded[..colon].to_owned(), decoded[colon + 1..].to_owned()))
+    }
+}
+
+impl Header for Authorization {
+    fn name() -> &'static str {
+        "Authorization"
+    }
+
+    fn parse_value(value: &str) -> Option<Authorization> {
+        let mut parts = value.splitn(2, ' ');
+        let scheme = parts.next().unwrap_or("");
+
+        if scheme.is_empty() || !scheme.bytes().all(is_token_char) {
+            return None;
+        }
+
+        Some(Authorization {
+            scheme: scheme.to_owned(),
+            credentials: parts.next().unwrap_or("").trim().to_owned(),
+        })
+    }
+
+    fn to_value(&self) -> String {
+        if self.credentials.is_empty() {
+            self.scheme.clone()
+        } else {
+            format!("{} {}", self.scheme, self.credentials)
+        }
+    }
+}
+
+/// The `Cache-Control` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, CacheControl};
+///
+/// let cache_control = CacheControl::parse_value("no-cache, max-age=60").unwrap();
+/// assert!(cache_control.has("no-cache"));
+/// assert_eq!(cache_control.max_age(), Some(60));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheControl(pub Vec<(String, Option<String>)>);
+
+impl CacheControl {
+    pub fn new() -> CacheControl {
+        CacheControl(Vec::new())
+    }
+
+    /// Add a directive, like `no-store` or `max-age` with a value
+    pub fn with_directive(&mut self, name: &str, value: Option<&str>) -> &mut Self {
+        self.0.push((name.to_lowercase(), value.map(|v| v.to_owned())));
+        self
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.0.iter().any(|&(ref n, _)| n.eq_ignore_ascii_case(name))
+    }
+
+    /// Value of the directive `name`, if it has one
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|&(_, ref value)| value.as_ref().map(|v| v.as_ref()))
+    }
+
+    pub fn max_age(&self) -> Option<u64> {
+        self.value("max-age").and_then(|age| age.parse().ok())
+    }
+}
+
+impl Header for CacheControl {
+    fn name() -> &'static str {
+        "Cache-Control"
+    }
+
+    fn parse_value(value: &str) -> Option<CacheControl> {
+        let mut directives = Vec::new();
+
+        for directive in split_list(value) {
+            let (name, value) = match directive.find('=') {
+                Some(eq) => (&directive[..eq], Some(unquote(&directive[eq + 1..]))),
+                None => (directive, None),
+            };
+
+            if name.is_empty() || !name.bytes().all(is_token_char) {
+                return None;
+            }
+
+            directives.push((name.to_lowercase(), value));
+        }
+
+        Some(CacheControl(directives))
     }
+
+    fn to_value(&self) -> String {
+        let directives: Vec<String> = self.0.iter().map(|&(ref name, ref value)| {
+            match *value {
+                Some(ref value) => format!("{}={}", name, quote_if_needed(value)),
+                None => name.clone(),
+            }
+        }).collect();
+
+        directives.join(", ")
+    }
+}
+
+/// The `Host` header
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, Host};
+///
+/// let host = Host::parse_value("[::1]:8080").unwrap();
+/// assert_eq!(host.host, "[::1]");
+/// assert_eq!(host.port, Some(8080));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Host {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Header for Host {
+    fn name() -> &'static str {
+        "Host"
+    }
+
+    fn parse_value(value: &str) -> Option<Host> {
+        // the colon of the port comes after the brackets of an IPv6 address
+        let port_start = if value.starts_with('[') {
+            match value.find(']') {
+                Some(end) => end + 1,
+                None => return None,
+            }
+        } else {
+            0
+        };
+
+        let (host, port) = match value[port_start..].find(':') {
+            Some(colon) => {
+                let colon = port_start + colon;
+                match value[colon + 1..].parse::<u16>() {
+                    Ok(port) => (&value[..colon], Some(port)),
+                    Err(_) => return None,
+                }
+            }
+            None => (value, None),
+        };
+
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/' || c == ',') {
+            return None;
+        }
+
+        Some(Host {
+            host: host.to_owned(),
+            port: port,
+        })
+    }
+
+    fn to_value(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        }
+    }
+}
+
+/// The `If-Modified-Since` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IfModifiedSince(pub Timespec);
+
+impl Header for IfModifiedSince {
+    fn name() -> &'static str {
+        "If-Modified-Since"
+    }
+
+    fn parse_value(value: &str) -> Option<IfModifiedSince> {
+        parse_http_date(value).map(IfModifiedSince)
+    }
+
+    fn to_value(&self) -> String {
+        format_http_date(self.0)
+    }
+}
+
+/// A range of bytes from the `Range` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    /// From the first to the last byte, inclusive
+    FromTo(u64, u64),
+    /// From a byte to the end
+    From(u64),
+    /// The last bytes
+    Last(u64),
+}
+
+/// The `Range` header, for byte ranges
+///
+/// #Examples
+///
+/// ```
+/// use http_server::headers::{Header, Range, ByteRange};
+///
+/// let range = Range::parse_value("bytes=0-9, 20-, -5").unwrap();
+/// assert_eq!(range.0, vec![ByteRange::FromTo(0, 9), ByteRange::From(20), ByteRange::Last(5)]);
+/// assert_eq!(range.satisfiable(30), vec![(0, 9), (20, 29), (25, 29)]);
+///
+/// assert!(Range::parse_value("bytesé=0-1").is_none());
+/// assert!(Range::parse_value("é").is_none());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Range(pub Vec<ByteRange>);
+
+impl Range {
+    /// The ranges that can be served from a representation of `len` bytes,
+    /// as the first and last byte of each
+    ///
+    /// An empty list means the range cannot be satisfied
+    pub fn satisfiable(&self, len: u64) -> Vec<(u64, u64)> {
+        self.0.iter().filter_map(|range| {
+            match *range {
+                ByteRange::FromTo(first, last) if first < len => Some((first, cmp::min(last, len - 1))),
+                ByteRange::From(first) if first < len => Some((first, len - 1)),
+                ByteRange::Last(suffix) if suffix > 0 && len > 0 => Some((len.saturating_sub(suffix), len - 1)),
+                _ => None,
+            }
+        }).collect()
+    }
+}
+
+impl Header for Range {
+    fn name() -> &'static str {
+        "Range"
+    }
+
+    fn parse_value(value: &str) -> Option<Range> {
+        let value = value.trim();
+        let unit = b"bytes=";
+        if !value.as_bytes().get(..unit.len()).map_or(false, |u| u.eq_ignore_ascii_case(unit)) {
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+
+        for spec in split_list(&value[unit.len()..]) {
+            let dash = match spec.find('-') {
+                Some(dash) => dash,
+                None => return None,
+            };
+            let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+            let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+                (Ok(first), Ok(last)) if first <= last => ByteRange::FromTo(first, last),
+                (Ok(first), Err(_)) if last.is_empty() => ByteRange::From(first),
+                (Err(_), Ok(last)) if first.is_empty() => ByteRange::Last(last),
+                _ => return None,
+            };
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return None;
+        }
+
+        Some(Range(ranges))
+    }
+
+    fn to_value(&self) -> String {
+        let ranges: Vec<String> = self.0.iter().map(|range| {
+            match *range {
+                ByteRange::FromTo(first, last) => format!("{}-{}", first, last),
+                ByteRange::From(first) => format!("{}-", first),
+                ByteRange::Last(suffix) => format!("-{}", suffix),
+            }
+        }).collect();
+
+        format!("bytes={}", ranges.join(","))
+    }
+}
+
+/// Parse a value followed by parameters, like `text/html; charset=utf-8`
+///
+/// Parameter names are turned to lower case and quoted values are unquoted
+fn parse_with_params(value: &str) -> Option<(&str, Vec<(String, String)>)> {
+    let mut parts = split_quoted(value, ';').into_iter();
+    let first = parts.next().unwrap_or("");
+
+    let mut params = Vec::new();
+    for param in parts {
+        let eq = match param.find('=') {
+            Some(eq) => eq,
+            None => return None,
+        };
+
+        let name = param[..eq].trim();
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return None;
+        }
+
+        params.push((name.to_lowercase(), unquote(param[eq + 1..].trim())));
+    }
+
+    Some((first, params))
+}
+
+fn parse_media_type(value: &str) -> Option<(String, Vec<(String, String)>)> {
+    let (mime, params) = match parse_with_params(value) {
+        Some(parsed) => parsed,
+        None => return None,
+    };
+
+    let valid_mime = match mime.find('/') {
+        Some(slash) => {
+            let (main_type, sub_type) = (&mime[..slash], &mime[slash + 1..]);
+            !main_type.is_empty() && !sub_type.is_empty()
+                && main_type.bytes().all(is_token_char) && sub_type.bytes().all(is_token_char)
+        }
+        None => false,
+    };
+
+    if !valid_mime {
+        return None;
+    }
+
+    Some((mime.to_lowercase(), params))
+}
+
+fn format_with_params(first: &str, params: &[(String, String)]) -> String {
+    let mut value = first.to_owned();
+    for &(ref name, ref param) in params {
+        value.push_str(&format!("; {}={}", name, quote_if_needed(param)));
+    }
+    value
+}
+
+/// Parse a list of values with an optional `q` parameter
+fn parse_quality_list(value: &str) -> Option<Vec<QualityItem>> {
+    let mut items = Vec::new();
+
+    for element in split_list(value) {
+        let mut params = split_quoted(element, ';');
+        let mut quality = 1.0;
+
+        if params.is_empty() {
+            continue;
+        }
+
+        // the weight is the last parameter, other parameters belong to the value
+        if let Some(last) = params.last().cloned() {
+            // bytes are compared, the parameter may not start with an ASCII character
+            if last.len() > 2 && last.as_bytes()[..2].eq_ignore_ascii_case(b"q=") {
+                quality = match last[2..].parse::<f32>() {
+                    Ok(q) if q >= 0.0 && q <= 1.0 => q,
+                    _ => return None,
+                };
+                params.pop();
+            }
+        }
+
+        if params.is_empty() || !params[0].bytes().all(|b| is_token_char(b) || b == b'/') {
+            return None;
+        }
+
+        items.push(QualityItem {
+            value: params.join(";").to_lowercase(),
+            quality: quality,
+        });
+    }
+
+    Some(items)
+}
+
+fn format_quality_list(items: &[QualityItem]) -> String {
+    let items: Vec<String> = items.iter().map(|item| {
+        if item.quality < 1.0 {
+            format!("{};q={}", item.value, item.quality)
+        } else {
+            item.value.clone()
+        }
+    }).collect();
+
+    items.join(", ")
+}
+
+fn negotiate<'a, F>(available: &[&'a str], quality: F) -> Option<&'a str> where F: Fn(&str) -> f32 {
+    let mut best: Option<(&'a str, f32)> = None;
+
+    for &candidate in available {
+        let q = quality(candidate);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+
+    best.map(|(candidate, _)| candidate)
+}
+
+/// Remove the quotes and escapes of a quoted string
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return value.to_owned();
+    }
+
+    let mut result = String::new();
+    let mut escaped = false;
+    for c in value[1..value.len() - 1].chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            result.push(c);
+            escaped = false;
+        }
+    }
+    result
+}
+
+fn quote_if_needed(value: &str) -> String {
+    if !value.is_empty() && value.bytes().all(is_token_char) {
+        return value.to_owned();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+const BASE64_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+
+    result
+}
+
+fn base64_decode(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim_right_matches('=');
+    let mut result = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for b in value.bytes() {
+        let digit = match BASE64_CHARS.iter().position(|&c| c == b) {
+            Some(digit) => digit as u32,
+            None => return None,
+        };
+
+        buffer = buffer << 6 | digit;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(result)
 }
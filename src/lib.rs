@@ -3,15 +3,23 @@ extern crate time;
 extern crate threadpool;
 extern crate url;
 extern crate conduit_mime_types;
+extern crate socket2;
+extern crate flate2;
+#[cfg(feature = "brotli")]
+extern crate brotli;
 
-pub use server::HttpServer;
+pub use server::{HttpServer, ServerConfig, ShutdownHandle, RunningServer};
 pub use request::Request;
-pub use response::Response;
+pub use response::{Response, Compression};
+pub use router::Router;
 
+pub mod error_page;
+pub mod form;
 pub mod handler;
 pub mod headers;
 pub mod parser;
 pub mod query;
 pub mod request;
 pub mod response;
+pub mod router;
 pub mod server;
@@ -1,23 +1,35 @@
 extern crate http_server;
 extern crate argparse;
+extern crate ctrlc;
 
 use std::env;
 use argparse::{ArgumentParser, Store, StoreTrue};
 
-use http_server::HttpServer;
-use http_server::handler::{Handler, ServerHandler, FileMode, DirectoryMode};
+use http_server::{HttpServer, Compression};
+use http_server::error_page::ErrorFiles;
+use http_server::handler::{Handler, ServerHandler, FileMode, DirectoryMode, SpaMode};
 
 const DEFAULT_ADDR: &'static str = "127.0.0.1:9000";
 
 fn main() {
     let mut addr = DEFAULT_ADDR.to_owned();
     let mut dir_mode = false;
+    let mut spa_mode = false;
+    let mut show_hidden = false;
+    let mut compress = false;
+    let mut precompressed = false;
+    let mut error_pages = false;
 
     {
         let mut parser = ArgumentParser::new();
         parser.set_description("tiny http server");
         parser.refer(&mut addr).add_option(&["-a", "--addr"], Store, "Address to listen");
         parser.refer(&mut dir_mode).add_option(&["-d", "--dir"], StoreTrue, "Enable directory listing within root");
+        parser.refer(&mut spa_mode).add_option(&["--spa"], StoreTrue, "Serve index.html for unknown pages of a single page application");
+        parser.refer(&mut show_hidden).add_option(&["--hidden"], StoreTrue, "Show hidden files in directory listing");
+        parser.refer(&mut compress).add_option(&["-z", "--compress"], StoreTrue, "Compress responses when the client accepts it");
+        parser.refer(&mut precompressed).add_option(&["--precompressed"], StoreTrue, "Serve .br and .gz copies of files when the client accepts them");
+        parser.refer(&mut error_pages).add_option(&["--error-pages"], StoreTrue, "Send error pages such as 404.html from the root");
         parser.parse_args_or_exit();
     }
 
@@ -27,11 +39,33 @@ fn main() {
     let handler: Box<Handler + Send + Sync>;
 
     if dir_mode {
-        handler = Box::new(ServerHandler::<DirectoryMode>::new(&path));
+        let mut dir_handler = ServerHandler::<DirectoryMode>::new(&path);
+        dir_handler.with_hidden_files(show_hidden).with_precompressed(precompressed);
+        handler = Box::new(dir_handler);
+    } else if spa_mode {
+        let mut spa_handler = ServerHandler::<SpaMode>::new(&path);
+        spa_handler.with_precompressed(precompressed);
+        handler = Box::new(spa_handler);
     } else {
-        handler = Box::new(ServerHandler::<FileMode>::new(&path));
+        let mut file_handler = ServerHandler::<FileMode>::new(&path);
+        file_handler.with_precompressed(precompressed);
+        handler = Box::new(file_handler);
     }
 
-    let server: HttpServer = HttpServer::new(&addr, 4usize);
+    let mut config = HttpServer::builder();
+    config.with_addr(&addr).with_threads(4);
+    if compress {
+        config.with_compression(Some(Compression::new()));
+    }
+    if error_pages {
+        config.with_error_renderer(ErrorFiles::new(&path));
+    }
+
+    let server: HttpServer = config.bind().ok().expect(format!("Could not bind to address {}", addr).as_ref());
+
+    // finish the requests in progress on SIGINT and SIGTERM
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown()).expect("Could not set the signal handler");
+
     server.start(handler);
 }
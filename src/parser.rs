@@ -1,23 +1,171 @@
 use std::error::Error;
-use std::io::{self, Read, BufRead, BufReader, ErrorKind};
+use std::io::{self, Read, BufRead, ErrorKind};
 use std::fmt;
 use regex::Regex;
 use url::percent_encoding;
 
+use ::headers;
+
+/// Limits on the size of a message
+///
+/// The defaults allow request lines of 8KB, 64KB of headers, 100 header
+/// fields and bodies of any size
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum length of the request line, including the line break
+    pub max_request_line: usize,
+    /// Maximum size of all header lines together
+    pub max_headers_size: usize,
+    /// Maximum number of header fields
+    pub max_headers: usize,
+    /// Maximum size of the body, not checked by the parser itself
+    pub max_body_size: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_headers_size: 64 * 1024,
+            max_headers: 100,
+            max_body_size: None,
+        }
+    }
+}
+
+/// Errors found while parsing a message
 #[derive(Debug)]
-pub struct ParseError {
-    description: String,
+pub enum ParseError {
+    /// The request line does not have a method, target and version
+    MalformedRequestLine(String),
+    /// The request line is longer than `Limits::max_request_line`
+    UriTooLong,
+    /// A header line is not a valid header field
+    InvalidHeader(String),
+    /// The header section is bigger than `Limits::max_headers_size`
+    HeaderTooLarge,
+    /// There are more header fields than `Limits::max_headers`
+    TooManyHeaders,
+    /// The body is bigger than `Limits::max_body_size`
+    BodyTooLarge,
+    /// The message was not received in time
+    Timeout,
+    /// The major version is not HTTP/1
+    UnsupportedVersion(String),
+    /// The message uses a transfer coding other than chunked
+    UnsupportedTransferEncoding(String),
+    /// The connection failed or was closed in the middle of the message
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// Status that should be sent to the client for this error
+    ///
+    /// Returns `None` when no response can be sent, because the
+    /// connection itself failed
+    ///
+    /// #Examples
+    ///
+    /// ```
+    /// use std::io::{self, Read, Write};
+    /// use std::net::TcpStream;
+    /// use http_server::{HttpServer, Request, Response};
+    ///
+    /// let server = HttpServer::builder()
+    ///     .with_addr("127.0.0.1:0")
+    ///     .with_max_request_line(64)
+    ///     .with_max_headers_size(128)
+    ///     .bind()
+    ///     .unwrap();
+    ///
+    /// let running = server.spawn(Box::new(|_: &mut Request, res: &mut Response| -> Result<(), io::Error> {
+    ///     res.start(|_| Ok(()))
+    /// }));
+    ///
+    /// let status_of = |request: &str| -> String {
+    ///     let mut stream = TcpStream::connect(running.local_addr()).unwrap();
+    ///     stream.write_all(request.as_bytes()).unwrap();
+    ///
+    ///     let mut response = String::new();
+    ///     stream.read_to_string(&mut response).unwrap();
+    ///     response.lines().next().unwrap_or("").to_owned()
+    /// };
+    ///
+    /// assert_eq!(status_of("GARBAGE\r\n\r\n"), "HTTP/1.0 400 Bad Request");
+    /// assert_eq!(status_of("GET ééééé HTTP/1.1\r\n\r\n"), "HTTP/1.0 400 Bad Request");
+    /// assert_eq!(status_of("GET /\u{e9} HTTP/1.1\r\nHost : x\r\n\r\n"), "HTTP/1.0 400 Bad Request");
+    ///
+    /// let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100));
+    /// assert_eq!(status_of(&long_path), "HTTP/1.0 414 URI Too Long");
+    ///
+    /// let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(200));
+    /// assert_eq!(status_of(&long_header), "HTTP/1.0 431 Request Header Fields Too Large");
+    ///
+    /// assert_eq!(status_of("GET / HTTP/2.0\r\n\r\n"), "HTTP/1.0 505 HTTP Version Not Supported");
+    /// ```
+    pub fn status(&self) -> Option<(i32, &'static str)> {
+        match *self {
+            ParseError::MalformedRequestLine(_) => Some((400, "Bad Request")),
+            ParseError::UriTooLong => Some((414, "URI Too Long")),
+            ParseError::InvalidHeader(_) => Some((400, "Bad Request")),
+            ParseError::HeaderTooLarge => Some((431, "Request Header Fields Too Large")),
+            ParseError::TooManyHeaders => Some((431, "Request Header Fields Too Large")),
+            ParseError::BodyTooLarge => Some((413, "Payload Too Large")),
+            ParseError::Timeout => Some((408, "Request Timeout")),
+            ParseError::UnsupportedVersion(_) => Some((505, "HTTP Version Not Supported")),
+            ParseError::UnsupportedTransferEncoding(_) => Some((501, "Not Implemented")),
+            // the message was not valid utf-8
+            ParseError::Io(ref e) if e.kind() == ErrorKind::InvalidData => Some((400, "Bad Request")),
+            ParseError::Io(_) => None,
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description)
+        match *self {
+            ParseError::MalformedRequestLine(ref line) => write!(f, "Malformed request line: '{}'", line),
+            ParseError::InvalidHeader(ref line) => write!(f, "Invalid header: '{}'", line),
+            ParseError::UnsupportedVersion(ref version) => write!(f, "Unsupported HTTP version: '{}'", version),
+            ParseError::UnsupportedTransferEncoding(ref coding) => {
+                write!(f, "Unsupported Transfer-Encoding: '{}'", coding)
+            }
+            ParseError::Io(ref e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
 impl Error for ParseError {
     fn description(&self) -> &str {
-        &self.description
+        match *self {
+            ParseError::MalformedRequestLine(_) => "Malformed request line",
+            ParseError::UriTooLong => "Request line too long",
+            ParseError::InvalidHeader(_) => "Invalid header",
+            ParseError::HeaderTooLarge => "Header section too large",
+            ParseError::TooManyHeaders => "Too many header fields",
+            ParseError::BodyTooLarge => "Body too large",
+            ParseError::Timeout => "Timed out reading message",
+            ParseError::UnsupportedVersion(_) => "Unsupported HTTP version",
+            ParseError::UnsupportedTransferEncoding(_) => "Unsupported Transfer-Encoding",
+            ParseError::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ParseError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> ParseError {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => ParseError::Timeout,
+            _ => ParseError::Io(error),
+        }
     }
 }
 
@@ -27,7 +175,7 @@ pub trait ParserHandler {
     fn on_query(&mut self, _query: &str) -> Result<(), ParseError> { Ok(()) }
     fn on_http_version(&mut self, _version: &str) -> Result<(), ParseError> { Ok(()) }
     fn on_status(&mut self, _status: u16) -> Result<(), ParseError> { Ok(()) }
-    fn on_header(&mut self, _field: &str, _values: Vec<&str>) -> Result<(), ParseError> { Ok(()) }
+    fn on_header(&mut self, _field: &str, _value: &str) -> Result<(), ParseError> { Ok(()) }
     fn on_body(&mut self, _part: &[u8]) -> Result<(), ParseError> { Ok(()) }
     fn on_headers_complete(&mut self) -> Result<(), ParseError> { Ok(()) }
     fn on_message_begin(&mut self) -> Result<(), ParseError> { Ok(()) }
@@ -36,27 +184,44 @@ pub trait ParserHandler {
 
 pub struct Parser<'a, H: 'a> {
     handler: &'a mut H,
+    limits: Limits,
 }
 
 impl<'a, H: ParserHandler> Parser<'a, H> {
     pub fn request(handler: &'a mut H) -> Parser<'a, H> {
-        Parser { handler: handler }
+        Parser { handler: handler, limits: Limits::default() }
     }
 
-    pub fn parse<R: Read>(&mut self, stream: &mut R) -> Result<(), Box<Error>> {
-        let mut buf_reader = BufReader::new(stream);
+    pub fn with_limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
 
+    /// Parse a single message from the stream
+    ///
+    /// The stream is taken as a `BufRead` so the caller can keep using the same
+    /// buffer for the next message on a persistent connection. If the stream
+    /// ends before a message begins, nothing is parsed and `Ok` is returned
+    pub fn parse<R: BufRead>(&mut self, stream: &mut R) -> Result<(), ParseError> {
         let mut request_line = String::new();
-        let bytes_read = try!(buf_reader.read_line(&mut request_line));
+        let max_request_line = self.limits.max_request_line;
+        let bytes_read = try!(stream.by_ref().take(max_request_line as u64).read_line(&mut request_line));
 
         if bytes_read == 0 || request_line.is_empty() {
             return Ok(());
         }
 
+        if !request_line.ends_with('\n') {
+            if bytes_read == max_request_line {
+                return Err(ParseError::UriTooLong);
+            }
+            return Err(ParseError::Io(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed")));
+        }
+
         try!(self.handler.on_message_begin());
 
         let re = Regex::new(
-            r"^(?P<method>[A-Z]*?) (?P<url>[^\?]+)(\?(?P<query>[^#]+))? HTTP/(?P<version>\d\.\d)\r\n$"
+            r"^(?P<method>[A-Z]+) (?P<url>[^\?]+)(\?(?P<query>[^#]+))? HTTP/(?P<version>\d\.\d)\r\n$"
         ).unwrap();
 
         match re.captures(&request_line) {
@@ -64,57 +229,88 @@ impl<'a, H: ParserHandler> Parser<'a, H> {
                 let method = cap.name("method").unwrap();
                 try!(self.handler.on_method(method));
 
-                let url = percent_encoding::lossy_utf8_percent_decode(
-                    cap.name("url").unwrap().as_bytes()
-                );
+                let url = match origin_form(cap.name("url").unwrap()) {
+                    Some(url) => percent_encoding::lossy_utf8_percent_decode(url.as_bytes()),
+                    None => return Err(ParseError::MalformedRequestLine(request_line.trim().to_owned())),
+                };
                 try!(self.handler.on_url(&url));
 
+                // the query is decoded after being split into parameters
                 match cap.name("query") {
-                    Some(query) => {
-                        let query = percent_encoding::lossy_utf8_percent_decode(query.as_bytes());
-                        try!(self.handler.on_query(&query));
-                    }
+                    Some(query) => try!(self.handler.on_query(query)),
                     None => {}
                 }
 
                 let version = cap.name("version").unwrap();
+                if !version.starts_with("1.") {
+                    return Err(ParseError::UnsupportedVersion(version.to_owned()));
+                }
                 try!(self.handler.on_http_version(version));
             },
             None => {
-                let error = io::Error::new(ErrorKind::InvalidInput, "Malformed Request");
-                return Err(Box::new(error));
+                return Err(ParseError::MalformedRequestLine(request_line.trim().to_owned()));
             },
         };
 
         // reading headers
-        for line in buf_reader.lines() {
-            match line {
-                Ok(header_line) => {
-                    // read an empty line
-                    if header_line.trim().len() == 0 {
-                        break;
-                    }
-                    let header: Vec<_> = header_line.split(": ").collect();
-
-                    if header.len() != 2 {
-                        let error = io::Error::new(
-                            ErrorKind::InvalidInput,
-                            format!("Invalid Header: '{}'", header_line),
-                        );
-                        return Err(Box::new(error));
-                    }
-
-                    let field = header[0];
-                    let values = header[1].split(',').map(|h| h.trim()).collect();
-
-                    try!(self.handler.on_header(field, values));
-                },
-                Err(e) => {
-                    return Err(Box::new(e));
+        let mut headers_size = 0;
+        let mut headers_count = 0;
+
+        loop {
+            let mut header_line = String::new();
+            let limit = (self.limits.max_headers_size - headers_size) as u64;
+            let bytes_read = try!(stream.by_ref().take(limit).read_line(&mut header_line));
+            headers_size += bytes_read;
+
+            if !header_line.ends_with('\n') {
+                if headers_size == self.limits.max_headers_size {
+                    return Err(ParseError::HeaderTooLarge);
                 }
+                return Err(ParseError::Io(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed")));
+            }
+
+            let header_line = header_line.trim_right_matches(|c| c == '\r' || c == '\n');
+
+            // read an empty line
+            if header_line.is_empty() {
+                break;
+            }
+
+            headers_count += 1;
+            if headers_count > self.limits.max_headers {
+                return Err(ParseError::TooManyHeaders);
             }
+
+            let (field, value) = match headers::parse_field(header_line) {
+                Some(field) => field,
+                None => return Err(ParseError::InvalidHeader(header_line.to_owned())),
+            };
+
+            try!(self.handler.on_header(field, value));
         }
 
+        try!(self.handler.on_headers_complete());
+
         Ok(())
     }
 }
+
+/// Turn the request target into a path
+///
+/// Targets in absolute form, as sent to proxies, have the scheme and
+/// authority removed. Returns `None` for targets that are not a path
+fn origin_form(url: &str) -> Option<&str> {
+    if url.starts_with('/') || url == "*" {
+        return Some(url);
+    }
+
+    for scheme in &["http://", "https://"] {
+        // bytes are compared, the target may not start with an ASCII character
+        if url.len() > scheme.len() && url.as_bytes()[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes()) {
+            let rest = &url[scheme.len()..];
+            return Some(rest.find('/').map_or("/", |i| &rest[i..]));
+        }
+    }
+
+    None
+}
@@ -1,56 +1,90 @@
-use std::collections::HashMap;
-
-use regex::Regex;
-
-#[derive(Debug)]
+use std::slice;
+use std::str::FromStr;
+
+use url::form_urlencoded;
+
+/// Parameters of a query string, in the `application/x-www-form-urlencoded` format
+///
+/// The query string is split into parameters before they are decoded, so
+/// encoded `&` and `=` are part of the names and values. A `+` is decoded
+/// as a space
+///
+/// #Examples
+///
+/// ```
+/// use http_server::query::Query;
+///
+/// let query = Query::from_str("q=a+b%26c&page=2&tag=x&tag=y");
+///
+/// assert_eq!(query.get_first("q"), Some("a b&c"));
+/// assert_eq!(query.get_as::<u32>("page"), Ok(Some(2)));
+/// assert_eq!(query.get("tag"), Some(vec!["x".to_owned(), "y".to_owned()]));
+/// assert_eq!(query.to_string(), "q=a+b%26c&page=2&tag=x&tag=y");
+/// ```
+#[derive(Clone, Debug, Default)]
 pub struct Query {
-    data: HashMap<String, Vec<String>>,
+    data: Vec<(String, String)>,
     query_string: Option<String>,
 }
 
-#[allow(dead_code)]
 impl Query {
     pub fn new() -> Query {
         Query {
-            data: HashMap::<String, Vec<String>>::new(),
+            data: Vec::new(),
             query_string: None
         }
     }
 
     pub fn from_str(query_string: &str) -> Query {
-        let mut data = HashMap::<String, Vec<String>>::new();
-
-        if query_string.trim().len() > 0 {
-            let re = Regex::new(r"([^=&]+)(=([^&]*))?").unwrap();
-            for cap in re.captures_iter(query_string) {
-                let key = cap.at(1).unwrap();
-                // TODO: Decode query string (see this http://unixpapa.com/js/querystring.html)
-                let val = cap.at(3).unwrap_or("");
-                let mut query_vec = data.entry(key.to_owned()).or_insert(Vec::new());
-                query_vec.push(val.to_owned());
-            }
-        }
-
         Query {
-            data: data,
-            query_string: None,
+            data: form_urlencoded::parse(query_string.as_bytes()),
+            query_string: Some(query_string.to_owned()),
         }
     }
 
+    /// Every value of the parameter `name`
     pub fn get(&self, name: &str) -> Option<Vec<String>> {
-        match self.data.get(name) {
-            Some(values) => {
-                if values.is_empty() {
-                    None
-                }
-                else {
-                    Some(values.clone())
-                }
-            },
-            None => None
+        let values: Vec<String> = self.iter()
+            .filter(|&(n, _)| n == name)
+            .map(|(_, value)| value.to_owned())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    /// The first value of the parameter `name`
+    pub fn get_first(&self, name: &str) -> Option<&str> {
+        self.iter().find(|&(n, _)| n == name).map(|(_, value)| value)
+    }
+
+    /// The first value of the parameter `name`, parsed as `T`
+    pub fn get_as<T: FromStr>(&self, name: &str) -> Result<Option<T>, T::Err> {
+        match self.get_first(name) {
+            Some(value) => value.parse().map(Some),
+            None => Ok(None),
         }
     }
 
+    pub fn has(&self, name: &str) -> bool {
+        self.get_first(name).is_some()
+    }
+
+    /// Add a value to the parameter `name`, keeping the previous ones
+    pub fn append(&mut self, name: &str, value: &str) -> &mut Self {
+        self.data.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Iterate over the decoded names and values, in order
+    pub fn iter(&self) -> Iter {
+        Iter { inner: self.data.iter() }
+    }
+
+    /// The query string as it was received, before decoding
     pub fn query_string(&self) -> Option<&str> {
         match self.query_string {
             Some(ref s) => Some(s),
@@ -59,41 +93,22 @@ impl Query {
     }
 }
 
-fn format_query_param(k: &str, v: &Vec<String>) -> String {
-    let mut result = String::new();
-
-    let mut k = k.to_string();
-    if v.len() > 1 {
-        k.push_str("[]");
-    }
+/// Iterator over the names and values of a `Query`
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (String, String)>,
+}
 
-    let mut iter = v.iter();
-    match iter.next() {
-        Some(i) => result.push_str(&format!("{}={}", k, i)),
-        None => return result,
-    };
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
 
-    for i in iter {
-        result.push_str(&format!("&{}={}", k, i));
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.inner.next().map(|&(ref name, ref value)| (name.as_ref(), value.as_ref()))
     }
-
-    result
 }
 
 impl ToString for Query {
+    /// Encode the parameters, in order, as a query string
     fn to_string(&self) -> String {
-        let mut result = String::new();
-
-        let mut iter = self.data.iter();
-        match iter.next() {
-            Some((k, v)) => result.push_str(&format_query_param(k, v)),
-            None => return result,
-        }
-
-        for (k, v) in iter {
-            result.push_str(&format!("&{}", format_query_param(k, v)));
-        }
-
-        result
+        form_urlencoded::serialize(&self.data)
     }
 }
@@ -1,7 +1,10 @@
-use std::io::Read;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, BufRead, BufReader, ErrorKind};
 use std::net::{SocketAddr, TcpStream};
 
-use super::headers::Headers;
+use super::form::{self, FormError, Multipart};
+use super::headers::{Headers, Header, HeaderError, ContentType};
 use super::query::Query;
 
 #[allow(dead_code)]
@@ -11,17 +14,18 @@ pub struct Request {
     scheme: String,
     path: Vec<String>,
     path_str: String,
+    params: HashMap<String, String>,
     query: Option<Query>,
     headers: Headers,
     content_length: Option<u64>,
-    stream: TcpStream,
+    body: Body,
 }
 
 impl Request {
     pub fn new(method: &str, scheme: &str, url: &str, query: Option<Query>,
                version: (u16, u16), headers: Headers,
                content_length: Option<u64>,
-               stream: &TcpStream) -> Self {
+               body: Body) -> Self {
 
        let path = url[1..url.len()].split('/').map(|x| x.to_owned()).collect();
 
@@ -31,10 +35,11 @@ impl Request {
            scheme: scheme.to_owned(),
            path: path,
            path_str: url.to_owned(),
+           params: HashMap::new(),
            headers: headers,
            query: query,
            content_length: content_length,
-           stream: stream.try_clone().unwrap(),
+           body: body,
        }
    }
 
@@ -51,7 +56,7 @@ impl Request {
     }
 
     pub fn host(&self) -> SocketAddr {
-        self.stream.local_addr().unwrap()
+        self.body.get_ref().local_addr().unwrap()
     }
 
     pub fn path(&self) -> &str {
@@ -62,23 +67,329 @@ impl Request {
         self.path.iter().map(|i| i.as_ref()).collect()
     }
 
+    /// Remove the first `count` components from `path_components`
+    ///
+    /// This is used to hand the rest of the path to a handler mounted under a
+    /// prefix. `path` is not changed. The removed components are returned so
+    /// they can be put back with `unshift_path`
+    pub fn shift_path(&mut self, count: usize) -> Vec<String> {
+        let count = cmp::min(count, self.path.len());
+        self.path.drain(..count).collect()
+    }
+
+    /// Put back the components removed by `shift_path`
+    pub fn unshift_path(&mut self, mut components: Vec<String>) {
+        components.extend(self.path.drain(..));
+        self.path = components;
+    }
+
+    /// Parameters captured from the path by a `Router`
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    pub fn params_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|p| p.as_ref())
+    }
+
     pub fn query(&self) -> &Option<Query> {
         &self.query
     }
 
     pub fn remote_addr(&self) -> SocketAddr {
-        self.stream.peer_addr().unwrap()
+        self.body.get_ref().peer_addr().unwrap()
     }
 
     pub fn content_length(&self) -> Option<u64> {
         self.content_length
     }
 
+    /// Whether the client wants the connection to stay open after this request
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends
+    /// `Connection: close`, while HTTP/1.0 clients must ask for it with
+    /// `Connection: keep-alive`
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.headers.get_list("Connection");
+        let has_token = |token: &str| connection.iter().any(|v| v.eq_ignore_ascii_case(token));
+
+        if self.http_version >= (1, 1) {
+            !has_token("close")
+        } else {
+            has_token("keep-alive")
+        }
+    }
+
     pub fn headers(&self) -> &Headers {
         &self.headers
     }
 
+    /// Get the header `H`, or `None` if the request does not have it
+    ///
+    /// See `Headers::typed`
+    pub fn typed_header<H: Header>(&self) -> Result<Option<H>, HeaderError> {
+        self.headers.typed()
+    }
+
+    /// Reader for the request body
+    ///
+    /// The reader stops at the end of the message, so it is safe to read it
+    /// to the end even when the connection is kept alive
     pub fn body<'a>(&'a mut self) -> &'a mut Read {
-        &mut self.stream
+        &mut self.body
+    }
+
+    /// Parse an `application/x-www-form-urlencoded` body
+    ///
+    /// Bodies bigger than `max_size` fail with `FormError::TooLarge`
+    pub fn form(&mut self, max_size: u64) -> Result<Query, FormError> {
+        let mime = try!(self.form_content_type()).mime;
+        if mime != "application/x-www-form-urlencoded" {
+            return Err(FormError::UnsupportedContentType(mime));
+        }
+
+        let body = try!(form::read_limited(&mut self.body, max_size));
+        Ok(Query::from_str(&String::from_utf8_lossy(&body)))
+    }
+
+    /// Read the parts of a `multipart/form-data` body
+    pub fn multipart(&mut self) -> Result<Multipart, FormError> {
+        let content_type = try!(self.form_content_type());
+        if content_type.mime != "multipart/form-data" {
+            return Err(FormError::UnsupportedContentType(content_type.mime));
+        }
+
+        let boundary = match content_type.param("boundary") {
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => boundary.to_owned(),
+            _ => return Err(FormError::Malformed("Missing multipart boundary".to_owned())),
+        };
+
+        Ok(Multipart::new(&mut self.body, &boundary))
+    }
+
+    fn form_content_type(&self) -> Result<ContentType, FormError> {
+        match self.typed_header::<ContentType>() {
+            Ok(Some(content_type)) => Ok(content_type),
+            Ok(None) => Err(FormError::UnsupportedContentType(String::new())),
+            Err(e) => Err(FormError::Malformed(e.to_string())),
+        }
+    }
+
+    pub fn into_body(self) -> Body {
+        self.body
+    }
+}
+
+/// Maximum length of a chunk size line, with its extensions
+const MAX_CHUNK_LINE: u64 = 4 * 1024;
+
+/// Maximum size of the trailers of a chunked body
+const MAX_TRAILERS_SIZE: u64 = 64 * 1024;
+
+/// How the length of a message body is determined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+/// Reader for a message body
+///
+/// It reads from the same buffer used to parse the request head, so bytes
+/// that were already buffered are not lost, and stops at the message boundary
+/// given by `Content-Length` or by the last chunk of a chunked body
+///
+/// Chunk extensions and trailers of chunked bodies are skipped
+///
+/// #Examples
+///
+/// ```
+/// use std::io::{self, Read, Write};
+/// use std::net::TcpStream;
+/// use http_server::{HttpServer, Request, Response};
+///
+/// let server = HttpServer::builder().with_addr("127.0.0.1:0").bind().unwrap();
+///
+/// // answer with the body, or with the error reading it
+/// let running = server.spawn(Box::new(|req: &mut Request, res: &mut Response| -> Result<(), io::Error> {
+///     let mut body = String::new();
+///     let body = match req.body().read_to_string(&mut body) {
+///         Ok(_) => body,
+///         Err(e) => e.to_string(),
+///     };
+///     res.with_header("Content-Length", &body.len().to_string());
+///     res.start(|res| res.write_all(body.as_bytes()))
+/// }));
+///
+/// // a chunked body, with an extension and a trailer, then a second request
+/// let mut stream = TcpStream::connect(running.local_addr()).unwrap();
+/// stream.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+///                    6;name=value\r\nhello \r\n5\r\nworld\r\n0\r\nChecksum: 1\r\n\r\n\
+///                    POST / HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc").unwrap();
+///
+/// let mut response = String::new();
+/// stream.read_to_string(&mut response).unwrap();
+/// let bodies: Vec<&str> = response.split("\r\n\r\n").collect();
+/// assert_eq!(bodies.len(), 3);
+/// assert!(bodies[1].starts_with("hello worldHTTP/1.1 200 OK"));
+/// assert_eq!(bodies[2], "abc");
+///
+/// // a chunk must be followed by a CRLF
+/// let mut stream = TcpStream::connect(running.local_addr()).unwrap();
+/// stream.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+///                    5\r\nhello0\r\n\r\n").unwrap();
+///
+/// let mut response = String::new();
+/// stream.read_to_string(&mut response).unwrap();
+/// assert!(response.ends_with("Missing CRLF after chunk"));
+/// ```
+#[derive(Debug)]
+pub struct Body {
+    reader: BufReader<TcpStream>,
+    kind: BodyKind,
+    remaining: u64,
+    total: u64,
+    max_size: Option<u64>,
+    done: bool,
+}
+
+impl Body {
+    pub fn new(reader: BufReader<TcpStream>, kind: BodyKind) -> Body {
+        let remaining = match kind {
+            BodyKind::Length(length) => length,
+            _ => 0,
+        };
+
+        Body {
+            reader: reader,
+            kind: kind,
+            remaining: remaining,
+            total: 0,
+            max_size: None,
+            done: remaining == 0 && kind != BodyKind::Chunked,
+        }
+    }
+
+    /// Fail reading a chunked body once it gets bigger than `max_size`
+    ///
+    /// The length of other bodies is known beforehand, so it is checked
+    /// before the request is handled
+    pub fn with_max_size(&mut self, max_size: Option<u64>) -> &mut Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn kind(&self) -> BodyKind {
+        self.kind
+    }
+
+    /// Whether the whole body was read
+    pub fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+
+    /// Returns the underlying reader, positioned after the bytes read so far
+    pub fn into_inner(self) -> BufReader<TcpStream> {
+        self.reader
+    }
+
+    fn read_chunk_size(&mut self) -> Result<u64, io::Error> {
+        let line = try!(self.read_chunk_line(MAX_CHUNK_LINE));
+
+        // chunk extensions are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+
+        u64::from_str_radix(size, 16).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, format!("Invalid chunk size: '{}'", size))
+        })
+    }
+
+    fn read_line_end(&mut self) -> Result<(), io::Error> {
+        let line = try!(self.read_chunk_line(MAX_CHUNK_LINE));
+
+        if line.trim().len() > 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Missing CRLF after chunk"));
+        }
+
+        Ok(())
+    }
+
+    fn read_trailers(&mut self) -> Result<(), io::Error> {
+        let mut trailers_size = 0;
+
+        loop {
+            let line = try!(self.read_chunk_line(MAX_TRAILERS_SIZE - trailers_size));
+            trailers_size += line.len() as u64;
+
+            if line.trim().len() == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Read a line of the chunked framing, up to `limit` bytes
+    fn read_chunk_line(&mut self, limit: u64) -> Result<String, io::Error> {
+        let mut line = String::new();
+        let bytes_read = try!(self.reader.by_ref().take(limit).read_line(&mut line));
+
+        if !line.ends_with('\n') {
+            if bytes_read as u64 == limit {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Chunk line too long"));
+            }
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed before end of body"));
+        }
+
+        Ok(line)
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.done || buf.len() == 0 {
+            return Ok(0);
+        }
+
+        if self.kind == BodyKind::Chunked && self.remaining == 0 {
+            self.remaining = try!(self.read_chunk_size());
+
+            // total never exceeds max, the size is compared to what is left to avoid overflows
+            if self.max_size.map_or(false, |max| self.remaining > max.saturating_sub(self.total)) {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Request body too large"));
+            }
+
+            if self.remaining == 0 {
+                try!(self.read_trailers());
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let bytes_read = try!(self.reader.read(&mut buf[..max]));
+
+        if bytes_read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed before end of body"));
+        }
+
+        self.remaining -= bytes_read as u64;
+        self.total += bytes_read as u64;
+
+        if self.remaining == 0 {
+            match self.kind {
+                BodyKind::Chunked => try!(self.read_line_end()),
+                _ => self.done = true,
+            }
+        }
+
+        Ok(bytes_read)
     }
 }
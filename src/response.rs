@@ -1,10 +1,108 @@
+use std::cmp;
 use std::error::Error;
 use std::io::{self, Write, BufWriter};
+use std::mem;
 use std::net::{TcpStream, Shutdown};
 
+#[cfg(feature = "brotli")]
+use brotli::CompressorWriter;
+use flate2;
+use flate2::write::{GzEncoder, ZlibEncoder};
 use time;
 
-use super::headers::Headers;
+use super::error_page::{ErrorFormat, ErrorPages};
+use super::headers::{Headers, Header, HeaderError, AcceptEncoding, ContentLength, ContentType};
+
+/// Content codings the server can compress with, by preference
+#[cfg(feature = "brotli")]
+const ENCODINGS: &'static [&'static str] = &["br", "gzip", "deflate"];
+#[cfg(not(feature = "brotli"))]
+const ENCODINGS: &'static [&'static str] = &["gzip", "deflate"];
+
+/// Settings to compress response bodies
+///
+/// Bodies are compressed with the best coding accepted by the client.
+/// Responses with a `Content-Encoding`, partial content, bodies smaller than
+/// the minimum size and media types that are already compressed, like
+/// images and archives, are sent as they are
+///
+/// #Examples
+///
+/// ```
+/// use http_server::{HttpServer, Compression};
+///
+/// let mut compression = Compression::new();
+/// compression.with_min_size(256).with_level(9);
+///
+/// let server = HttpServer::builder()
+///     .with_addr("127.0.0.1:0")
+///     .with_compression(Some(compression))
+///     .bind()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+    skip_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            level: 6,
+            skip_types: Vec::new(),
+        }
+    }
+
+    /// Set the size under which bodies with a `Content-Length` are not compressed
+    ///
+    /// The default is 1KB
+    pub fn with_min_size(&mut self, min_size: u64) -> &mut Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Set the compression level, from 0 to 9. The default is 6
+    pub fn with_level(&mut self, level: u32) -> &mut Self {
+        self.level = cmp::min(level, 9);
+        self
+    }
+
+    /// Never compress responses with the media type `mime`
+    ///
+    /// A type ending with `/*`, like `font/*`, skips all its subtypes
+    pub fn with_skip_type(&mut self, mime: &str) -> &mut Self {
+        self.skip_types.push(mime.to_lowercase());
+        self
+    }
+
+    /// Whether bodies with the media type `mime` are worth compressing
+    pub fn compresses(&self, mime: &str) -> bool {
+        let mime = mime.to_lowercase();
+
+        let skipped = self.skip_types.iter().any(|skip| {
+            *skip == mime || (skip.ends_with("/*") && mime.starts_with(&skip[..skip.len() - 1]))
+        });
+
+        !skipped && !is_compressed_type(&mime)
+    }
+}
+
+/// Media types whose content is already compressed
+fn is_compressed_type(mime: &str) -> bool {
+    if mime == "image/svg+xml" {
+        return false;
+    }
+
+    mime.starts_with("image/") || mime.starts_with("audio/") || mime.starts_with("video/")
+        || mime == "font/woff" || mime == "font/woff2"
+        || mime == "application/octet-stream" || mime == "application/pdf"
+        || mime == "application/zip" || mime == "application/gzip" || mime == "application/x-gzip"
+        || mime == "application/x-bzip2" || mime == "application/x-xz" || mime == "application/zstd"
+        || mime == "application/x-7z-compressed" || mime == "application/x-rar-compressed"
+}
 
 #[derive(Debug)]
 pub struct Response {
@@ -12,8 +110,15 @@ pub struct Response {
     status: i32,
     status_text: String,
     headers: Headers,
+    default_headers: Headers,
     stream: BufWriter<TcpStream>,
     headers_written: bool,
+    keep_alive: bool,
+    head: bool,
+    compression: Option<Compression>,
+    accept_encoding: Option<AcceptEncoding>,
+    error_pages: ErrorPages,
+    error_format: ErrorFormat,
 }
 
 impl Response {
@@ -25,8 +130,15 @@ impl Response {
             status: 200,
             status_text: "OK".to_owned(),
             headers: Headers::new(),
+            default_headers: Headers::new(),
             stream: BufWriter::new(stream),
             headers_written: false,
+            keep_alive: false,
+            head: false,
+            compression: None,
+            accept_encoding: None,
+            error_pages: ErrorPages::default(),
+            error_format: ErrorFormat::Text,
         })
     }
 
@@ -65,22 +177,212 @@ impl Response {
         self
     }
 
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Get the header `H`, or `None` if it was not set
+    pub fn typed_header<H: Header>(&self) -> Result<Option<H>, HeaderError> {
+        self.headers.typed()
+    }
+
+    /// Set the header `H`, replacing any other with the same name
+    pub fn with_typed_header<H: Header>(&mut self, header: &H) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+        self.headers.set_typed(header);
+        self
+    }
+
+    /// Add a header, keeping any other with the same name
     pub fn with_header(&mut self, name: &str, value: &str) -> &mut Self {
         if self.headers_written {
             panic!("Cannot write header to started response")
         }
-        self.headers.insert(name, value);
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Add a header that is only sent if no header with the same name was set
+    pub fn with_default_header(&mut self, name: &str, value: &str) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+        self.default_headers.append(name, value);
+        self
+    }
+
+    /// Whether the connection will be kept open after this response
+    ///
+    /// Before the response is started this is only what was requested with
+    /// `with_keep_alive`. Once started, it is `false` if the body could only be
+    /// delimited by closing the connection
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Whether the status line and headers were sent
+    pub fn is_started(&self) -> bool {
+        self.headers_written
+    }
+
+    /// Whether this is the response to a HEAD request
+    pub fn is_head(&self) -> bool {
+        self.head
+    }
+
+    /// Mark this as the response to a HEAD request
+    ///
+    /// The headers are sent as they would be for a GET, including
+    /// `Content-Length`, but whatever is written to the body is discarded
+    pub fn with_head(&mut self, head: bool) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.head = head;
+        self
+    }
+
+    /// Compress the body, if the client accepts it
+    ///
+    /// `None` disables compression, which is the default
+    pub fn with_compression(&mut self, compression: Option<Compression>) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.compression = compression;
+        self
+    }
+
+    /// Set the codings the client accepts, from the request's `Accept-Encoding`
+    ///
+    /// Without it the body is never compressed
+    pub fn with_accept_encoding(&mut self, accept_encoding: Option<AcceptEncoding>) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.accept_encoding = accept_encoding;
+        self
+    }
+
+    /// Set the renderer of the pages sent with `send_error`
+    pub fn with_error_pages(&mut self, error_pages: ErrorPages) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.error_pages = error_pages;
+        self
+    }
+
+    /// Set the format of the pages sent with `send_error`, from the request's `Accept`
+    ///
+    /// The default is plain text
+    pub fn with_error_format(&mut self, error_format: ErrorFormat) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.error_format = error_format;
+        self
+    }
+
+    /// Send the page for the error `status`, in the format the client accepts
+    ///
+    /// Headers describing a representation that was about to be sent, like
+    /// `Content-Encoding` and `ETag`, are removed, since the page replaces it
+    pub fn send_error(&mut self, status: i32, description: &str) -> Result<(), io::Error> {
+        let format = self.error_format;
+        let page = self.error_pages.render(status, description, format);
+
+        self.with_status(status, description);
+
+        for name in REPRESENTATION_HEADERS {
+            self.headers.remove(name);
+        }
+        self.headers.set("Content-Type", format.content_type());
+        self.headers.set("Content-Length", &page.len().to_string());
+        self.add_vary("Accept");
+
+        self.start(|res| {
+            try!(res.write_all(page.as_bytes()));
+            try!(res.flush());
+            Ok(())
+        })
+    }
+
+    pub fn with_keep_alive(&mut self, keep_alive: bool) -> &mut Self {
+        if self.headers_written {
+            panic!("Cannot write header to started response")
+        }
+
+        self.keep_alive = keep_alive;
         self
     }
 
+    /// Send the status line and headers, then call `cb` to write the body
+    ///
+    /// If no `Content-Length` was set, the body of an HTTP/1.1 response is
+    /// sent with `Transfer-Encoding: chunked`, so it can be streamed without
+    /// closing the connection. Older clients get the body delimited by the end
+    /// of the connection instead
     pub fn start<F>(&mut self, cb: F) -> Result<(), io::Error>
-            where F: FnOnce(&mut BufWriter<TcpStream>) -> Result<(), io::Error> {
+            where F: FnOnce(&mut BodyWriter) -> Result<(), io::Error> {
         if self.headers_written {
             panic!("Response already started");
         }
 
-        self.with_header("Date", &time::now_utc().rfc822().to_string())
-            .with_header("Connection", "close");
+        let has_body = self.status >= 200 && self.status != 204 && self.status != 304;
+
+        // a 304 gets the same validators as the response it stands for
+        let not_modified = self.status == 304;
+
+        let encoding = self.negotiate_encoding(has_body || not_modified);
+        if let Some(coding) = encoding {
+            // the length of the compressed body is not known in advance
+            self.headers.remove("Content-Length");
+            if has_body {
+                self.headers.set("Content-Encoding", coding);
+            }
+
+            // the compressed body is not byte for byte the same representation
+            let weak_etag = self.headers.get("ETag")
+                .and_then(|etag| if etag.starts_with("W/") { None } else { Some(format!("W/{}", etag)) });
+            if let Some(etag) = weak_etag {
+                self.headers.set("ETag", &etag);
+            }
+        }
+
+        let encoding = if has_body { encoding } else { None };
+
+        let chunked = has_body
+            && self.http_version == "1.1"
+            && !self.headers.has("Content-Length")
+            && !self.headers.has("Transfer-Encoding");
+
+        if chunked {
+            self.with_header("Transfer-Encoding", "chunked");
+        }
+
+        self.keep_alive = self.keep_alive
+            && (!has_body || self.head || chunked || self.headers.has("Content-Length"));
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+
+        if !self.headers.has("Date") {
+            self.headers.append("Date", &time::now_utc().rfc822().to_string());
+        }
+        self.headers.set("Connection", connection);
+
+        let defaults: Vec<(&str, &str)> = self.default_headers.iter()
+            .filter(|&(name, _)| !self.headers.has(name))
+            .collect();
+        for (name, value) in defaults {
+            self.headers.append(name, value);
+        }
 
         self.headers_written = true;
 
@@ -90,8 +392,256 @@ impl Response {
         try!(self.stream.write(format!("{}", self.headers.to_string()).as_bytes()));
         try!(self.stream.write(b"\r\n"));
 
-        let result = cb(&mut self.stream);
-        try!(self.stream.get_mut().shutdown(Shutdown::Both));
+        let result = {
+            let level = self.compression.as_ref().map_or(0, |c| c.level);
+            let mut writer = BodyWriter::new(&mut self.stream, chunked, self.head);
+            if let Some(coding) = encoding {
+                writer.encoder = Encoder::new(coding, level);
+            }
+            cb(&mut writer).and_then(|_| writer.finish())
+        };
+
+        // a body that was cut short cannot be delimited anymore
+        if result.is_err() {
+            self.keep_alive = false;
+        }
+
+        try!(self.stream.flush());
+
+        if !self.keep_alive {
+            try!(self.stream.get_mut().shutdown(Shutdown::Both));
+        }
+
         result
     }
 }
+
+impl Response {
+    /// Choose the coding to compress the body with, if any
+    fn negotiate_encoding(&mut self, has_body: bool) -> Option<&'static str> {
+        let (min_size, compresses) = match self.compression {
+            Some(ref compression) => {
+                let mime = match self.headers.typed::<ContentType>() {
+                    Ok(Some(content_type)) => content_type.mime,
+                    _ => return None,
+                };
+                (compression.min_size, compression.compresses(&mime))
+            }
+            None => return None,
+        };
+
+        if !has_body || !compresses || self.status == 206 || self.headers.has("Content-Encoding") {
+            return None;
+        }
+
+        if let Ok(Some(ContentLength(length))) = self.headers.typed::<ContentLength>() {
+            if length < min_size {
+                return None;
+            }
+        }
+
+        // from here on the body depends on the Accept-Encoding of the request
+        self.add_vary("Accept-Encoding");
+
+        match self.accept_encoding {
+            Some(ref accept) => accept.negotiate(ENCODINGS),
+            None => None,
+        }
+    }
+
+    /// Add `name` to the `Vary` header, unless it is already there
+    fn add_vary(&mut self, name: &str) {
+        let varies = self.headers.get_list("Vary").iter()
+            .any(|v| *v == "*" || v.eq_ignore_ascii_case(name));
+        if !varies {
+            self.headers.append("Vary", name);
+        }
+    }
+}
+
+/// Headers about the body of a response, which do not apply to an error page
+const REPRESENTATION_HEADERS: &'static [&'static str] = &["Content-Encoding", "ETag", "Last-Modified", "Accept-Ranges"];
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Compressor for the body, writing to memory
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(coding: &str, level: u32) -> Option<Encoder> {
+        let compression = flate2::Compression::new(level);
+
+        match coding {
+            "gzip" => Some(Encoder::Gzip(GzEncoder::new(Vec::new(), compression))),
+            "deflate" => Some(Encoder::Deflate(ZlibEncoder::new(Vec::new(), compression))),
+            #[cfg(feature = "brotli")]
+            "br" => Some(Encoder::Brotli(Box::new(CompressorWriter::new(Vec::new(), 4096, level, 22)))),
+            _ => None,
+        }
+    }
+
+    fn writer(&mut self) -> &mut Write {
+        match *self {
+            Encoder::Gzip(ref mut encoder) => encoder,
+            Encoder::Deflate(ref mut encoder) => encoder,
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(ref mut encoder) => &mut **encoder,
+        }
+    }
+
+    /// Take the compressed data produced so far
+    fn take_output(&mut self) -> Vec<u8> {
+        let output = match *self {
+            Encoder::Gzip(ref mut encoder) => encoder.get_mut(),
+            Encoder::Deflate(ref mut encoder) => encoder.get_mut(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(ref mut encoder) => encoder.get_mut(),
+        };
+        mem::replace(output, Vec::new())
+    }
+
+    /// End the compressed stream, returning the remaining data
+    fn finish(self) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// Writer for the response body
+///
+/// When the response is chunked, written data is buffered and sent in chunks
+/// of up to 8KB, or whenever the writer is flushed. When the response is
+/// compressed, data is compressed before being sent. For HEAD requests,
+/// written data is discarded
+pub struct BodyWriter<'a> {
+    stream: &'a mut BufWriter<TcpStream>,
+    chunked: bool,
+    discard: bool,
+    buffer: Vec<u8>,
+    encoder: Option<Encoder>,
+}
+
+impl<'a> BodyWriter<'a> {
+    fn new(stream: &'a mut BufWriter<TcpStream>, chunked: bool, discard: bool) -> BodyWriter<'a> {
+        BodyWriter {
+            stream: stream,
+            chunked: chunked && !discard,
+            discard: discard,
+            buffer: Vec::new(),
+            encoder: None,
+        }
+    }
+
+    /// Whether the body is sent with chunked transfer encoding
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    /// Whether the body is compressed
+    pub fn is_compressed(&self) -> bool {
+        self.encoder.is_some()
+    }
+
+    fn write_chunk(&mut self) -> Result<(), io::Error> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        try!(write!(self.stream, "{:X}\r\n", self.buffer.len()));
+        try!(self.stream.write_all(&self.buffer));
+        try!(self.stream.write_all(b"\r\n"));
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    /// Write data as it is sent, after compression
+    ///
+    /// All of `buf` is written or buffered, chunks are sent as they fill up
+    fn write_all_encoded(&mut self, mut buf: &[u8]) -> Result<(), io::Error> {
+        if !self.chunked {
+            return self.stream.write_all(buf);
+        }
+
+        while !buf.is_empty() {
+            let len = cmp::min(buf.len(), CHUNK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+
+            if self.buffer.len() == CHUNK_SIZE {
+                try!(self.write_chunk());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write any buffered data and the terminating chunk
+    fn finish(&mut self) -> Result<(), io::Error> {
+        if let Some(encoder) = self.encoder.take() {
+            let output = try!(encoder.finish());
+            if !self.discard {
+                try!(self.write_all_encoded(&output));
+            }
+        }
+
+        if self.chunked {
+            try!(self.write_chunk());
+            try!(self.stream.write_all(b"0\r\n\r\n"));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Write for BodyWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if self.discard {
+            return Ok(buf.len());
+        }
+
+        let output = match self.encoder {
+            Some(ref mut encoder) => {
+                try!(encoder.writer().write_all(buf));
+                encoder.take_output()
+            }
+            None => {
+                try!(self.write_all_encoded(buf));
+                return Ok(buf.len());
+            }
+        };
+
+        try!(self.write_all_encoded(&output));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        if self.discard {
+            return self.stream.flush();
+        }
+
+        let output = match self.encoder {
+            Some(ref mut encoder) => {
+                try!(encoder.writer().flush());
+                encoder.take_output()
+            }
+            None => Vec::new(),
+        };
+        try!(self.write_all_encoded(&output));
+
+        if self.chunked {
+            try!(self.write_chunk());
+        }
+
+        self.stream.flush()
+    }
+}
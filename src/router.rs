@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io;
+
+use ::handler::Handler;
+use ::request::Request;
+use ::response::Response;
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Route {
+    method: String,
+    pattern: Vec<Segment>,
+    handler: Box<Handler + Send + Sync>,
+}
+
+struct Mount {
+    prefix: Vec<String>,
+    handler: Box<Handler + Send + Sync>,
+}
+
+/// Handler that dispatches requests to other handlers based on method and path
+///
+/// Patterns are matched against the path components. A component starting
+/// with `:` captures a single component, while one starting with `*` captures
+/// the rest of the path and must be the last one. Captured values are available
+/// with `Request::param`.
+///
+/// Requests matching no route are passed to the first handler mounted under a
+/// prefix of the path. If nothing matches, a 404 is sent, or a 405 with the
+/// `Allow` header if the path matches a route for other methods.
+///
+/// #Examples
+///
+/// ```
+/// use std::env;
+/// use std::io::{self, Write};
+/// use http_server::{Request, Response, Router};
+/// use http_server::handler::{ServerHandler, FileMode};
+///
+/// let root = env::home_dir().unwrap();
+///
+/// let mut router = Router::new();
+/// router.get("/users/:id", |req: &mut Request, res: &mut Response| {
+///     let message = format!("user {}", req.param("id").unwrap());
+///     res.start(|res| {
+///         try!(res.write_all(message.as_bytes()));
+///         Ok(())
+///     })
+/// });
+/// router.mount("/static", ServerHandler::<FileMode>::new(&root));
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<Mount>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            mounts: Vec::new(),
+        }
+    }
+
+    /// Add a route for the given method and path pattern
+    pub fn route<H>(&mut self, method: &str, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.routes.push(Route {
+            method: method.to_owned(),
+            pattern: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn patch<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.route("PATCH", pattern, handler)
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Pass every request under `prefix` to `handler`
+    ///
+    /// The prefix is removed from the request's `path_components`, so a
+    /// `ServerHandler` mounted on `/static` serves `/static/app.js` from
+    /// `app.js` in its root
+    pub fn mount<H>(&mut self, prefix: &str, handler: H) -> &mut Self
+            where H: Handler + Send + Sync + 'static {
+        let prefix = prefix.split('/').filter(|c| !c.is_empty()).map(|c| c.to_owned()).collect();
+
+        self.mounts.push(Mount {
+            prefix: prefix,
+            handler: Box::new(handler),
+        });
+        self
+    }
+}
+
+impl Handler for Router {
+    fn handle_request(&self, req: &mut Request, res: &mut Response) -> Result<(), io::Error> {
+        let mut allowed: Vec<&str> = Vec::new();
+
+        for route in self.routes.iter() {
+            let params = match match_pattern(&route.pattern, &req.path_components()) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            // GET routes also answer HEAD, the response discards the body
+            let is_head_of_get = req.method() == "HEAD" && route.method == "GET";
+
+            if route.method != req.method() && !is_head_of_get {
+                if !allowed.contains(&route.method.as_ref()) {
+                    allowed.push(&route.method);
+                }
+                if route.method == "GET" && !allowed.contains(&"HEAD") {
+                    allowed.push("HEAD");
+                }
+                continue;
+            }
+
+            req.params_mut().extend(params);
+            return route.handler.handle_request(req, res);
+        }
+
+        if !allowed.is_empty() {
+            res.with_header("Allow", &allowed.join(", "));
+            return res.send_error(405, "Method Not Allowed");
+        }
+
+        for mount in self.mounts.iter() {
+            let matches = {
+                let path = req.path_components();
+                path.len() >= mount.prefix.len()
+                    && mount.prefix.iter().zip(path.iter()).all(|(p, c)| p == c)
+            };
+
+            if matches {
+                let shifted = req.shift_path(mount.prefix.len());
+                let result = mount.handler.handle_request(req, res);
+                req.unshift_path(shifted);
+                return result;
+            }
+        }
+
+        res.send_error(404, "Not Found")
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let pattern = if pattern.starts_with('/') { &pattern[1..] } else { pattern };
+
+    pattern.split('/').map(|segment| {
+        if segment.starts_with(':') {
+            Segment::Param(segment[1..].to_owned())
+        } else if segment.starts_with('*') {
+            Segment::Rest(segment[1..].to_owned())
+        } else {
+            Segment::Static(segment.to_owned())
+        }
+    }).collect()
+}
+
+fn match_pattern(pattern: &[Segment], path: &[&str]) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+
+    for (i, segment) in pattern.iter().enumerate() {
+        match *segment {
+            Segment::Rest(ref name) => {
+                params.insert(name.clone(), path[i..].join("/"));
+                return Some(params);
+            }
+            Segment::Param(ref name) => {
+                match path.get(i) {
+                    Some(component) if !component.is_empty() => {
+                        params.insert(name.clone(), (*component).to_owned());
+                    }
+                    _ => return None,
+                }
+            }
+            Segment::Static(ref value) => {
+                if path.get(i) != Some(&value.as_ref()) {
+                    return None;
+                }
+            }
+        }
+    }
+
+    if pattern.len() == path.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
@@ -1,43 +1,79 @@
 use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::cmp;
+use std::io::{self, Read, BufRead, BufReader, ErrorKind};
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{mpsc, Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use socket2::{Socket, Domain, Type, Protocol};
 use threadpool::ThreadPool;
 
-use ::response::Response;
-use ::request::{Request};
+use ::response::{Response, Compression};
+use ::request::{Request, Body, BodyKind};
 use ::handler::Handler;
-use ::headers::Headers;
+use ::error_page::{ErrorFormat, ErrorPages, ErrorRenderer};
+use ::headers::{Headers, Accept, ContentLength, AcceptEncoding};
 use ::query::Query;
-use ::parser::{Parser, ParserHandler, ParseError};
+use ::parser::{Parser, ParserHandler, ParseError, Limits};
 
 #[derive(Default)]
 struct HttpParserHandler {
+    started: bool,
     method: String,
     url: String,
     query: Option<String>,
     version: String,
-    headers: HashMap<String, Vec<String>>,
+    headers: Headers,
 }
 
 impl HttpParserHandler {
-    pub fn build_request(&self, stream: &TcpStream) -> Request {
+    pub fn build_request(&self, reader: BufReader<TcpStream>, max_body_size: Option<u64>) -> Result<Request, ParseError> {
         let version_vec: Vec<&str> = self.version.split('.').collect();
         let http_version = (version_vec[0].parse().unwrap(), version_vec[1].parse().unwrap());
         let query = self.query.clone().map(|q| Query::from_str(&q));
-        Request::new(
+
+        let content_length = try!(self.headers.typed::<ContentLength>()).map(|length| length.0);
+
+        // Transfer-Encoding takes precedence over Content-Length
+        let body_kind = match self.headers.get_list("Transfer-Encoding").last() {
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => BodyKind::Chunked,
+            Some(coding) => return Err(ParseError::UnsupportedTransferEncoding(coding.to_string())),
+            None => match content_length {
+                Some(length) => BodyKind::Length(length),
+                None => BodyKind::Empty,
+            },
+        };
+
+        if let (BodyKind::Length(length), Some(max)) = (body_kind, max_body_size) {
+            if length > max {
+                return Err(ParseError::BodyTooLarge);
+            }
+        }
+
+        let mut body = Body::new(reader, body_kind);
+        body.with_max_size(max_body_size);
+
+        Ok(Request::new(
             &self.method,
             "http",
             &self.url,
             query,
             http_version,
-            Headers::with_data(self.headers.clone()),
-            None,
-            stream,
-        )
+            self.headers.clone(),
+            content_length,
+            body,
+        ))
     }
 }
 
 impl ParserHandler for HttpParserHandler {
+    fn on_message_begin(&mut self) -> Result<(), ParseError> {
+        self.started = true;
+        Ok(())
+    }
+
     fn on_method(&mut self, method: &str) -> Result<(), ParseError> {
         self.method = method.to_owned();
         Ok(())
@@ -58,12 +94,274 @@ impl ParserHandler for HttpParserHandler {
         Ok(())
     }
 
-    fn on_header(&mut self, field: &str, values: Vec<&str>) -> Result<(), ParseError> {
-        self.headers.insert(field.to_owned(), values.into_iter().map(|val| val.to_owned()).collect());
+    fn on_header(&mut self, field: &str, value: &str) -> Result<(), ParseError> {
+        self.headers.append(field, value);
         Ok(())
     }
 }
 
+/// Configuration for an `HttpServer`
+///
+/// Created with `HttpServer::builder()`. Once configured, `bind` creates
+/// the server listening on the given addresses
+///
+/// #Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use http_server::HttpServer;
+///
+/// let server = HttpServer::builder()
+///     .with_addr("127.0.0.1:0")
+///     .with_threads(8)
+///     .with_read_timeout(Some(Duration::from_secs(10)))
+///     .with_max_body_size(Some(1024 * 1024))
+///     .with_server_header(Some("tiny-http"))
+///     .bind()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    addrs: Vec<String>,
+    num_threads: usize,
+    backlog: i32,
+    reuse_address: bool,
+    reuse_port: bool,
+    nodelay: bool,
+    server_header: Option<String>,
+    compression: Option<Compression>,
+    error_pages: ErrorPages,
+    keep_alive_timeout: Option<Duration>,
+    max_keep_alive_requests: usize,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    limits: Limits,
+}
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig {
+            addrs: Vec::new(),
+            num_threads: 4,
+            backlog: 128,
+            reuse_address: true,
+            reuse_port: false,
+            nodelay: false,
+            server_header: None,
+            compression: None,
+            error_pages: ErrorPages::default(),
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            max_keep_alive_requests: 100,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            shutdown_timeout: Some(Duration::from_secs(30)),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Add an address to listen on
+    ///
+    /// Can be called more than once to listen on several addresses
+    pub fn with_addr(&mut self, addr: &str) -> &mut Self {
+        self.addrs.push(addr.to_owned());
+        self
+    }
+
+    /// Set the number of threads handling connections. The default is 4
+    pub fn with_threads(&mut self, num_threads: usize) -> &mut Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Set the size of the queue of connections waiting to be accepted. The default is 128
+    pub fn with_backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listening sockets. The default is `true`
+    pub fn with_reuse_address(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_address = reuse;
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the listening sockets, where supported
+    ///
+    /// This allows several servers to listen on the same port. The default is `false`
+    pub fn with_reuse_port(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted connections. The default is `false`
+    pub fn with_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Set the `Server` header sent when the handler does not set one
+    ///
+    /// The default is `None`, meaning no header is sent
+    pub fn with_server_header(&mut self, server: Option<&str>) -> &mut Self {
+        self.server_header = server.map(|s| s.to_owned());
+        self
+    }
+
+    /// Compress response bodies when the client accepts it
+    ///
+    /// Handlers can still change it for each response with
+    /// `Response::with_compression`. The default is `None`, meaning no compression
+    pub fn with_compression(&mut self, compression: Option<Compression>) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Render the pages of the errors sent by the server and its handlers with `renderer`
+    ///
+    /// This includes the errors for requests that cannot be parsed. The page
+    /// is sent as HTML, JSON or plain text, depending on the `Accept` header
+    pub fn with_error_renderer<R>(&mut self, renderer: R) -> &mut Self
+            where R: ErrorRenderer + Send + Sync + 'static {
+        self.error_pages = ErrorPages::new(renderer);
+        self
+    }
+
+    /// Set how long a persistent connection may stay idle waiting for the next request
+    ///
+    /// `None` means the connection can wait forever. The default is 5 seconds
+    pub fn with_keep_alive_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Set how many requests can be served on a single connection
+    ///
+    /// After the last request the connection is closed. Setting it to `1`
+    /// disables persistent connections. The default is 100
+    pub fn with_max_keep_alive_requests(&mut self, max: usize) -> &mut Self {
+        self.max_keep_alive_requests = max;
+        self
+    }
+
+    /// Set how long a client has to send the request line and headers
+    ///
+    /// It is also the longest the server waits for each read of the body.
+    /// `None` means no timeout. The default is 30 seconds
+    pub fn with_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a write to the client can block
+    ///
+    /// `None` means no timeout. The default is 30 seconds
+    pub fn with_write_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Set how long a shutdown waits for the requests in progress
+    ///
+    /// Connections still open after that are closed. `None` means waiting
+    /// until all of them finish. The default is 30 seconds
+    pub fn with_shutdown_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum length of the request line
+    ///
+    /// Longer requests get a 414 response. The default is 8KB
+    pub fn with_max_request_line(&mut self, max: usize) -> &mut Self {
+        self.limits.max_request_line = max;
+        self
+    }
+
+    /// Set the maximum size of the header section
+    ///
+    /// Requests with bigger headers get a 431 response. The default is 64KB
+    pub fn with_max_headers_size(&mut self, max: usize) -> &mut Self {
+        self.limits.max_headers_size = max;
+        self
+    }
+
+    /// Set the maximum number of header fields
+    ///
+    /// Requests with more headers get a 431 response. The default is 100
+    pub fn with_max_headers(&mut self, max: usize) -> &mut Self {
+        self.limits.max_headers = max;
+        self
+    }
+
+    /// Set the maximum size of request bodies
+    ///
+    /// Requests with a bigger `Content-Length` get a 413 response, while
+    /// chunked bodies fail to be read once they get bigger. The default is `None`,
+    /// meaning no limit
+    pub fn with_max_body_size(&mut self, max: Option<u64>) -> &mut Self {
+        self.limits.max_body_size = max;
+        self
+    }
+
+    /// Create the server, listening on every configured address
+    pub fn bind(&self) -> Result<HttpServer, io::Error> {
+        if self.addrs.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "No address to listen on"));
+        }
+
+        if self.num_threads == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "The number of threads must be greater than 0"));
+        }
+
+        let mut listeners = Vec::new();
+        let mut local_addrs = Vec::new();
+        for addr in self.addrs.iter() {
+            let listener = try!(self.listen(addr));
+            local_addrs.push(try!(listener.local_addr()));
+            listeners.push(listener);
+        }
+
+        Ok(HttpServer {
+            listeners: listeners,
+            shutdown: ShutdownHandle {
+                requested: Arc::new(AtomicBool::new(false)),
+                addrs: Arc::new(local_addrs),
+            },
+            threadpool: ThreadPool::new(self.num_threads),
+            config: Arc::new(self.clone()),
+        })
+    }
+
+    /// Bind to the first address `addr` resolves to that can be bound
+    fn listen(&self, addr: &str) -> Result<TcpListener, io::Error> {
+        let mut last_error = io::Error::new(ErrorKind::InvalidInput, format!("Could not resolve address {}", addr));
+
+        for socket_addr in try!(addr.to_socket_addrs()) {
+            match self.listen_on(&socket_addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn listen_on(&self, addr: &SocketAddr) -> Result<TcpListener, io::Error> {
+        let socket = try!(Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP)));
+
+        try!(socket.set_reuse_address(self.reuse_address));
+
+        #[cfg(unix)]
+        try!(socket.set_reuse_port(self.reuse_port));
+
+        try!(socket.bind(&(*addr).into()));
+        try!(socket.listen(self.backlog));
+
+        Ok(socket.into())
+    }
+}
+
 /// Server that listen for connections on given address
 ///
 /// The server will listen for connections on the given address,
@@ -82,23 +380,51 @@ impl ParserHandler for HttpParserHandler {
 /// let server = HttpServer::new("127.0.0.1:9000", 4);
 ///
 /// ```
-#[allow(dead_code)]
 pub struct HttpServer {
-    addr: String,
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
+    shutdown: ShutdownHandle,
     threadpool: ThreadPool,
+    config: Arc<ServerConfig>,
 }
 
 impl HttpServer {
     /// Creates a new instance of HttpServer
+    ///
+    /// Panics if the address cannot be bound, use `builder` to handle the error
     pub fn new(addr: &str, num_threads: usize) -> HttpServer {
-        let listener = TcpListener::bind(addr).ok().expect(format!("Could not bind to address {}", addr).as_ref());
+        HttpServer::builder()
+            .with_addr(addr)
+            .with_threads(num_threads)
+            .bind()
+            .ok().expect(format!("Could not bind to address {}", addr).as_ref())
+    }
 
-        HttpServer {
-            addr: addr.to_string(),
-            listener: listener,
-            threadpool: ThreadPool::new(num_threads),
-        }
+    /// Creates the configuration for a new server
+    pub fn builder() -> ServerConfig {
+        ServerConfig::new()
+    }
+
+    /// Get a handle that can shut down the server from another thread
+    ///
+    /// #Examples
+    ///
+    /// ```
+    /// use std::io;
+    /// use std::thread::{self, JoinHandle};
+    /// use http_server::{HttpServer, Request, Response};
+    ///
+    /// let server = HttpServer::builder().with_addr("127.0.0.1:0").bind().unwrap();
+    /// let shutdown = server.shutdown_handle();
+    ///
+    /// thread::spawn(move || shutdown.shutdown());
+    ///
+    /// // returns once the shutdown is requested and the connections are closed
+    /// server.start(Box::new(|_: &mut Request, res: &mut Response| -> Result<(), io::Error> {
+    ///     res.start(|_| Ok(()))
+    /// }));
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     /// Start the server with the given handler
@@ -106,40 +432,525 @@ impl HttpServer {
     /// When started, the server will block and listen for connections,
     /// creating the request and response and passing them to the handler
     /// when a client connects
+    ///
+    /// Connections are kept open for further requests when the client asks
+    /// for it, until the keep alive timeout or the maximum number of requests
+    /// per connection is reached
+    ///
+    /// The server runs until a shutdown is requested through a `ShutdownHandle`
     pub fn start(&self, handler: Box<Handler + Send + Sync>) {
         let arc = Arc::new(handler);
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    let handler = arc.clone();
+        let connections = Arc::new(Connections::default());
+        let (sender, receiver) = mpsc::channel();
 
-                    self.threadpool.execute(move || {
-                        let mut http_parser = HttpParserHandler::default();
+        // each listener accepts on its own thread, so all of them
+        // are served while this thread hands connections to the pool
+        for listener in self.listeners.iter() {
+            let listener = match listener.try_clone() {
+                Ok(listener) => listener,
+                Err(e) => {
+                    println!("Error listening: '{}'", e);
+                    continue;
+                }
+            };
+            let sender = sender.clone();
+            let shutdown = self.shutdown.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.is_shutdown() || sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
 
-                        Parser::request(&mut http_parser).parse(&mut stream).unwrap_or_else(|e| {
-                            println!("Erro parsing request: '{}'", e);
-                        });
+        drop(sender);
 
-                        let mut request = http_parser.build_request(&stream);
-                        let mut response = Response::from_stream(&stream).unwrap();
+        while !self.shutdown.is_shutdown() {
+            let stream = match receiver.recv_timeout(Duration::from_millis(SHUTDOWN_POLL_INTERVAL)) {
+                Ok(stream) => stream,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
 
-                        handler.handle_request(&mut request, &mut response).unwrap_or_else(|e| {
-                            println!("Error handling request: '{}'", e);
-                        });
+            // connections accepted after the shutdown are not served
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+
+            match stream {
+                Ok(stream) => {
+                    let guard = match Connections::add(&connections, &stream) {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            println!("Error handling connection: '{}'", e);
+                            continue;
+                        }
+                    };
+                    let handler = arc.clone();
+                    let config = self.config.clone();
+                    let shutdown = self.shutdown.clone();
+
+                    self.threadpool.execute(move || {
+                        handle_connection(stream, &**handler, &config, &shutdown);
+                        drop(guard);
                     });
                 },
                 Err(error) => println!("{:?}", error),
             }
         }
+
+        let closed = connections.drain(self.config.shutdown_timeout);
+        if closed > 0 {
+            println!("Shutdown timed out, closed {} connections", closed);
+        }
     }
 
+    /// Request the server to shut down
+    ///
+    /// Same as calling `shutdown` on the server's `ShutdownHandle`
     pub fn stop(&self) {
-        drop(&self.listener);
+        self.shutdown.shutdown();
+    }
+
+    /// The addresses the server is listening on
+    ///
+    /// Useful to know the port when binding to port 0
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.shutdown.addrs
+    }
+
+    /// Start the server on a new thread
+    ///
+    /// The returned `RunningServer` shuts the server down when dropped
+    ///
+    /// #Examples
+    ///
+    /// ```
+    /// use std::io::{self, Read, Write};
+    /// use std::net::TcpStream;
+    /// use http_server::{HttpServer, Request, Response};
+    ///
+    /// let server = HttpServer::builder().with_addr("127.0.0.1:0").bind().unwrap();
+    ///
+    /// let running = server.spawn(Box::new(|_: &mut Request, res: &mut Response| -> Result<(), io::Error> {
+    ///     res.with_header("Content-Length", "5");
+    ///     res.start(|res| res.write_all(b"hello"))
+    /// }));
+    ///
+    /// let mut stream = TcpStream::connect(running.local_addr()).unwrap();
+    /// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    ///
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    /// assert!(response.starts_with("HTTP/1.0 200 OK"));
+    /// assert!(response.ends_with("hello"));
+    /// ```
+    pub fn spawn(self, handler: Box<Handler + Send + Sync>) -> RunningServer {
+        let shutdown = self.shutdown.clone();
+        let thread = thread::spawn(move || self.start(handler));
+
+        RunningServer {
+            shutdown: shutdown,
+            thread: Some(thread),
+        }
     }
 }
 
-impl Drop for HttpServer {
+/// Server running on a background thread, created by `HttpServer::spawn`
+///
+/// Dropping it shuts the server down and waits for it to stop
+pub struct RunningServer {
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RunningServer {
+    /// The first address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shutdown.addrs[0]
+    }
+
+    /// All the addresses the server is listening on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.shutdown.addrs
+    }
+
+    /// Get a handle that can shut down the server from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shut the server down and wait for it to stop
+    pub fn shutdown(mut self) -> thread::Result<()> {
+        self.shutdown.shutdown();
+        self.wait()
+    }
+
+    /// Wait for the server to stop, without requesting it
+    ///
+    /// Blocks until a `ShutdownHandle` of this server is used
+    pub fn join(mut self) -> thread::Result<()> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> thread::Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        let _ = self.wait();
+    }
+}
+
+/// How often waiting threads check if a shutdown was requested
+const SHUTDOWN_POLL_INTERVAL: u64 = 100;
+
+/// Handle to shut down a running `HttpServer`
+///
+/// It can be cloned and sent to other threads. Once shut down, the server
+/// stops accepting connections, closes idle persistent connections and
+/// waits for the requests in progress to finish before `start` returns.
+/// Connections still open after the shutdown timeout are closed
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    addrs: Arc<Vec<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Request the server to shut down, without waiting for it
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // the accepting threads are blocked until a client
+        // connects, so connect to each listener to wake them up
+        for addr in self.addrs.iter() {
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                let loopback = match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                };
+                addr.set_ip(loopback);
+            }
+
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_millis(SHUTDOWN_POLL_INTERVAL));
+        }
+    }
+
+    /// Whether a shutdown was requested
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// Connections being served, so a shutdown can wait for them
+#[derive(Default)]
+struct Connections {
+    streams: Mutex<(usize, HashMap<usize, TcpStream>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    /// Register a connection until the returned guard is dropped
+    fn add(connections: &Arc<Connections>, stream: &TcpStream) -> Result<ConnectionGuard, io::Error> {
+        let stream = try!(stream.try_clone());
+        let mut streams = connections.streams.lock().unwrap();
+
+        let id = streams.0;
+        streams.0 += 1;
+        streams.1.insert(id, stream);
+
+        Ok(ConnectionGuard {
+            connections: connections.clone(),
+            id: id,
+        })
+    }
+
+    /// Wait for every connection to finish, up to `timeout`
+    ///
+    /// Returns how many connections had to be closed after the timeout
+    fn drain(&self, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut streams = self.streams.lock().unwrap();
+
+        while !streams.1.is_empty() {
+            streams = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.closed.wait_timeout(streams, deadline - now).unwrap().0
+                },
+                None => self.closed.wait(streams).unwrap(),
+            };
+        }
+
+        for stream in streams.1.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        streams.1.len()
+    }
+}
+
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: usize,
+}
+
+impl Drop for ConnectionGuard {
     fn drop(&mut self) {
-        self.stop();
+        let mut streams = self.connections.streams.lock().unwrap();
+        streams.1.remove(&self.id);
+        self.connections.closed.notify_all();
     }
 }
+
+/// Serve requests from a connection until it is closed
+fn handle_connection(stream: TcpStream, handler: &(Handler + Send + Sync), config: &ServerConfig,
+                     shutdown: &ShutdownHandle) {
+    let result = stream.set_write_timeout(config.write_timeout)
+        .and_then(|_| stream.set_nodelay(config.nodelay));
+
+    if let Err(e) = result {
+        println!("Error handling connection: '{}'", e);
+        return;
+    }
+
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(e) => {
+            println!("Error handling connection: '{}'", e);
+            return;
+        }
+    };
+
+    let max_requests = config.max_keep_alive_requests;
+    let mut served = 0;
+
+    while served < max_requests {
+        if served > 0 && !wait_for_request(&mut reader, config.keep_alive_timeout, shutdown) {
+            break;
+        }
+
+        // waiting for the request leaves a short timeout on the socket,
+        // which the head reader only replaces when there is a deadline
+        if stream.set_read_timeout(config.read_timeout).is_err() {
+            break;
+        }
+
+        let mut http_parser = HttpParserHandler::default();
+
+        let result = {
+            let mut head_reader = DeadlineReader::new(&mut reader, config.read_timeout);
+            Parser::request(&mut http_parser).with_limits(config.limits).parse(&mut head_reader)
+        };
+
+        if let Err(e) = result {
+            println!("Error parsing request: '{}'", e);
+            send_parse_error(&stream, &e, config, error_format(&http_parser.headers));
+            break;
+        }
+
+        // connection closed before sending another request
+        if !http_parser.started {
+            break;
+        }
+
+        served += 1;
+
+        if stream.set_read_timeout(config.read_timeout).is_err() {
+            break;
+        }
+
+        let mut request = match http_parser.build_request(reader, config.limits.max_body_size) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Error parsing request: '{}'", e);
+                send_parse_error(&stream, &e, config, error_format(&http_parser.headers));
+                break;
+            }
+        };
+
+        let mut response = match Response::from_stream(&stream) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Error handling connection: '{}'", e);
+                break;
+            }
+        };
+
+        if request.http_version() >= (1, 1) {
+            response.with_http_version("1.1");
+        }
+
+        if let Some(ref server) = config.server_header {
+            response.with_default_header("Server", server);
+        }
+
+        response.with_compression(config.compression.clone())
+            .with_accept_encoding(request.typed_header::<AcceptEncoding>().unwrap_or(None));
+
+        response.with_error_pages(config.error_pages.clone())
+            .with_error_format(error_format(request.headers()));
+
+        response.with_head(request.method() == "HEAD")
+            .with_keep_alive(request.keep_alive() && served < max_requests && !shutdown.is_shutdown());
+
+        let result = handler.handle_request(&mut request, &mut response);
+        if let Err(ref e) = result {
+            println!("Error handling request: '{}'", e);
+        }
+
+        // the client would wait for a response that never comes
+        if !response.is_started() {
+            response.with_keep_alive(false);
+            response.send_error(500, "Internal Server Error").unwrap_or_else(|e| {
+                println!("Error sending response: '{}'", e);
+            });
+            break;
+        }
+
+        // after an error the response may be incomplete
+        if result.is_err() || !response.keep_alive() {
+            break;
+        }
+
+        // skip what the handler did not read of the body
+        // so the next request starts at the right place
+        let mut body = request.into_body();
+        if io::copy(&mut body, &mut io::sink()).is_err() {
+            break;
+        }
+
+        reader = body.into_inner();
+    }
+}
+
+/// Wait for the next request on a persistent connection
+///
+/// Returns `false` if the connection is closed, stays idle longer than
+/// `timeout` or the server is shutting down
+fn wait_for_request(reader: &mut BufReader<TcpStream>, timeout: Option<Duration>, shutdown: &ShutdownHandle) -> bool {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    // wait in small steps to notice a shutdown while idle
+    loop {
+        if shutdown.is_shutdown() {
+            return false;
+        }
+
+        let mut wait = Duration::from_millis(SHUTDOWN_POLL_INTERVAL);
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            wait = cmp::min(wait, deadline - now);
+        }
+
+        if reader.get_ref().set_read_timeout(Some(wait)).is_err() {
+            return false;
+        }
+
+        match reader.fill_buf() {
+            Ok(buf) => return !buf.is_empty(),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                || e.kind() == ErrorKind::TimedOut
+                || e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Reader that fails with `TimedOut` once a deadline has passed
+///
+/// The read timeout of the socket only limits each read, so without this
+/// a client could keep a thread busy by sending the request very slowly
+struct DeadlineReader<'a> {
+    reader: &'a mut BufReader<TcpStream>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineReader<'a> {
+    fn new(reader: &'a mut BufReader<TcpStream>, timeout: Option<Duration>) -> DeadlineReader<'a> {
+        DeadlineReader {
+            reader: reader,
+            deadline: timeout.map(|t| Instant::now() + t),
+        }
+    }
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let bytes_read = {
+            let available = try!(self.fill_buf());
+            let len = cmp::min(available.len(), buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            len
+        };
+
+        self.consume(bytes_read);
+        Ok(bytes_read)
+    }
+}
+
+impl<'a> BufRead for DeadlineReader<'a> {
+    fn fill_buf(&mut self) -> Result<&[u8], io::Error> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Timed out reading request"));
+            }
+
+            try!(self.reader.get_ref().set_read_timeout(Some(deadline - now)));
+        }
+
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount)
+    }
+}
+
+/// Format of the error pages for a request with `headers`
+fn error_format(headers: &Headers) -> ErrorFormat {
+    // an invalid header is ignored, as if it was not sent
+    ErrorFormat::from_accept(headers.typed::<Accept>().unwrap_or(None).as_ref())
+}
+
+/// Answer a request that could not be parsed and close the connection
+fn send_parse_error(stream: &TcpStream, error: &ParseError, config: &ServerConfig, format: ErrorFormat) {
+    let (status, description) = match error.status() {
+        Some(status) => status,
+        None => return,
+    };
+
+    let mut response = match Response::from_stream(stream) {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error handling connection: '{}'", e);
+            return;
+        }
+    };
+
+    response.with_error_pages(config.error_pages.clone())
+        .with_error_format(format);
+
+    response.send_error(status, description).unwrap_or_else(|e| {
+        println!("Error sending response: '{}'", e);
+    });
+}